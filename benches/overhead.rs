@@ -0,0 +1,183 @@
+//! Cost of going through QADAPT compared to using the `System` allocator directly.
+//!
+//! Every workload runs twice: once with allocations going straight to `System`, and once
+//! through QADAPT. Release builds only keep allocation tracking; run with
+//! `cargo bench --features release-checks` to include the protection checks, which is
+//! what the `protected` group measures.
+//!
+//! Results are written by criterion to `target/criterion/<group>/<function>/new/estimates.json`;
+//! use `--save-baseline <name>` and `--baseline <name>` to compare against an earlier run.
+#![allow(deprecated)]
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use qadapt::allow_alloc;
+use qadapt::assert_no_alloc;
+use qadapt::protect;
+use qadapt::protect_with_policy;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::hint::black_box;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Barrier;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Global allocator that forwards to either `System` or QADAPT, so the same workloads
+/// can be measured with both. QADAPT uses `System` underneath, so memory can be freed
+/// by either one.
+struct Switch;
+
+static USE_QADAPT: AtomicBool = AtomicBool::new(false);
+
+unsafe impl GlobalAlloc for Switch {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if USE_QADAPT.load(Ordering::Relaxed) {
+            QADAPT.alloc(layout)
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if USE_QADAPT.load(Ordering::Relaxed) {
+            QADAPT.dealloc(ptr, layout)
+        } else {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if USE_QADAPT.load(Ordering::Relaxed) {
+            QADAPT.alloc_zeroed(layout)
+        } else {
+            System.alloc_zeroed(layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if USE_QADAPT.load(Ordering::Relaxed) {
+            QADAPT.realloc(ptr, layout, new_size)
+        } else {
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+}
+
+#[global_allocator]
+static A: Switch = Switch;
+
+const ALLOCATORS: [&str; 2] = ["system", "qadapt"];
+const THREADS: [usize; 3] = [2, 4, 8];
+
+/// Send the global allocator's requests to `System` or QADAPT
+fn use_allocator(name: &str) {
+    USE_QADAPT.store(name == "qadapt", Ordering::Relaxed);
+}
+
+fn alloc_dealloc() {
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = std::alloc::alloc(black_box(layout));
+        std::alloc::dealloc(black_box(ptr), layout);
+    }
+}
+
+fn vec_growth() {
+    let mut v = Vec::new();
+    for i in 0..1024u64 {
+        v.push(i);
+    }
+    black_box(v);
+}
+
+fn string_format() {
+    let i = black_box(12345);
+    black_box(format!("{}: {:?} {:.2}", i, "qadapt", 1.5f64));
+}
+
+/// Run `alloc_dealloc` on several threads at once, and measure how long it takes
+/// for all of them to finish
+fn contended(threads: usize, iters: u64) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..iters {
+                    alloc_dealloc();
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for h in handles {
+        h.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn unprotected(c: &mut Criterion) {
+    let workloads: [(&str, fn()); 3] = [
+        ("alloc_dealloc", alloc_dealloc),
+        ("vec_growth", vec_growth),
+        ("string_format", string_format),
+    ];
+    for (name, workload) in workloads.iter() {
+        let mut group = c.benchmark_group(*name);
+        for allocator in ALLOCATORS.iter() {
+            use_allocator(allocator);
+            group.bench_function(*allocator, |b| b.iter(workload));
+        }
+        group.finish();
+    }
+}
+
+fn multi_thread(c: &mut Criterion) {
+    let mut group = c.benchmark_group("alloc_dealloc_threads");
+    for allocator in ALLOCATORS.iter() {
+        use_allocator(allocator);
+        for &threads in THREADS.iter() {
+            group.bench_with_input(BenchmarkId::new(*allocator, threads), &threads, |b, &t| {
+                b.iter_custom(|iters| contended(t, iters))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn protected(c: &mut Criterion) {
+    use_allocator("qadapt");
+    let mut group = c.benchmark_group("protected");
+    group.bench_function("enter_exit", |b| b.iter(|| drop(protect())));
+    group.bench_function("assert_no_alloc", |b| {
+        b.iter(|| assert_no_alloc!(black_box(2u64) * 2))
+    });
+    group.bench_function("allowed_alloc_dealloc", |b| {
+        b.iter(|| {
+            let _guard = protect();
+            allow_alloc!(alloc_dealloc());
+        })
+    });
+    group.bench_function("counted_alloc_dealloc", |b| {
+        b.iter(|| {
+            let _guard = protect_with_policy(ViolationPolicy::Count);
+            alloc_dealloc();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, unprotected, multi_thread, protected);
+criterion_main!(benches);
@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 use qadapt::no_alloc;
 use qadapt::QADAPT;
 
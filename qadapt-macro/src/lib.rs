@@ -9,181 +9,820 @@
 // #![deny(missing_docs)]
 extern crate proc_macro;
 
-use proc_macro::Delimiter;
-use proc_macro::Group;
-use proc_macro::Spacing;
 use proc_macro::TokenStream;
-use proc_macro::TokenTree;
-use std::iter::FromIterator;
-
-macro_rules! group {
-    ($delim:expr, $ts:expr) => {{
-        let _tt: TokenTree = ::proc_macro::Group::new($delim, $ts).into();
-        _tt
-    }};
-    ($delim:expr) => {
-        group!($delim, ::proc_macro::TokenStream::new())
-    };
+use proc_macro2::Span;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use quote::quote_spanned;
+use quote::ToTokens;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+use syn::visit_mut;
+use syn::visit_mut::VisitMut;
+use syn::Attribute;
+use syn::Block;
+use syn::Error;
+use syn::Expr;
+use syn::ExprAsync;
+use syn::ExprAwait;
+use syn::ExprClosure;
+use syn::GenericArgument;
+use syn::Ident;
+use syn::ImplItem;
+use syn::Item;
+use syn::ItemImpl;
+use syn::ItemMod;
+use syn::LitStr;
+use syn::Meta;
+use syn::MetaNameValue;
+use syn::PathArguments;
+use syn::ReturnType;
+use syn::Signature;
+use syn::Stmt;
+use syn::Token;
+use syn::Type;
+use syn::TypeParamBound;
+
+/// Generate the body of a function that is protected from making allocations.
+///
+/// The protected region lasts as long as the guard returned by `protect`,
+/// so it's exited no matter how the function body finishes: falling off the end,
+/// `return`, `?`, or a panic. Closures and nested functions inside the body are
+/// left alone, since they aren't run as part of the body itself.
+fn protected_body(protect: TokenStream2, body: &Block) -> TokenStream2 {
+    quote! {{
+        let __qadapt_guard = #protect;
+        // Bodies that always `return` would otherwise trip this lint for user code
+        #[allow(clippy::diverging_sub_expression)]
+        let __ret__ = #body;
+        // When `return` statements are involved, this code can get marked as
+        // unreachable because of early exit
+        #[allow(unreachable_code)]
+        {
+            __ret__
+        }
+    }}
+}
+
+/// Generate a call to one of the QADAPT functions that creates a guard.
+/// The call is located at `span`, which is where the region is reported to be entered.
+fn protect_call(name: &str, span: Span, args: TokenStream2) -> TokenStream2 {
+    let name = Ident::new(name, span);
+    quote_spanned!(span=> ::qadapt::#name(#args))
 }
 
-macro_rules! ident {
-    ($name:expr, $span:expr) => {{
-        let _tt: TokenTree = ::proc_macro::Ident::new($name, $span).into();
-        _tt
-    }};
-    ($name:expr) => {
-        ident!($name, ::proc_macro::Span::call_site())
+/// Generate a reference to a `static` that caches the counters for the region entered
+/// by a call, so they're only looked up the first time it's made
+fn region_slot() -> TokenStream2 {
+    quote!({
+        static SLOT: ::qadapt::__RegionSlot = ::qadapt::__RegionSlot::new();
+        &SLOT
+    })
+}
+
+/// Generate `concat!(module_path!(), "::name")`, the path of the function being protected
+fn fn_path(name: &str, span: Span) -> TokenStream2 {
+    let suffix = LitStr::new(&format!("::{}", name), span);
+    quote!(concat!(module_path!(), #suffix))
+}
+
+/// Generate `::core::option::Option::Some(value)`, or `None` if there's no value
+fn option_value(value: Option<impl ToTokens>) -> TokenStream2 {
+    match value {
+        Some(v) => quote!(::core::option::Option::Some(#v)),
+        None => quote!(::core::option::Option::None),
+    }
+}
+
+/// Remove the `#[allow_alloc]` attribute from a list, returning whether it was there
+fn take_allow_attr(attrs: &mut Vec<Attribute>) -> bool {
+    let before = attrs.len();
+    attrs.retain(|a| !a.path().is_ident("allow_alloc"));
+    attrs.len() != before
+}
+
+/// Get the attributes of an expression statement
+fn expr_attrs(expr: &mut Expr) -> Option<&mut Vec<Attribute>> {
+    let attrs = match expr {
+        Expr::Array(e) => &mut e.attrs,
+        Expr::Assign(e) => &mut e.attrs,
+        Expr::Async(e) => &mut e.attrs,
+        Expr::Await(e) => &mut e.attrs,
+        Expr::Binary(e) => &mut e.attrs,
+        Expr::Block(e) => &mut e.attrs,
+        Expr::Break(e) => &mut e.attrs,
+        Expr::Call(e) => &mut e.attrs,
+        Expr::Cast(e) => &mut e.attrs,
+        Expr::Closure(e) => &mut e.attrs,
+        Expr::Const(e) => &mut e.attrs,
+        Expr::Continue(e) => &mut e.attrs,
+        Expr::Field(e) => &mut e.attrs,
+        Expr::ForLoop(e) => &mut e.attrs,
+        Expr::Group(e) => &mut e.attrs,
+        Expr::If(e) => &mut e.attrs,
+        Expr::Index(e) => &mut e.attrs,
+        Expr::Let(e) => &mut e.attrs,
+        Expr::Lit(e) => &mut e.attrs,
+        Expr::Loop(e) => &mut e.attrs,
+        Expr::Macro(e) => &mut e.attrs,
+        Expr::Match(e) => &mut e.attrs,
+        Expr::MethodCall(e) => &mut e.attrs,
+        Expr::Paren(e) => &mut e.attrs,
+        Expr::Path(e) => &mut e.attrs,
+        Expr::Range(e) => &mut e.attrs,
+        Expr::Reference(e) => &mut e.attrs,
+        Expr::Repeat(e) => &mut e.attrs,
+        Expr::Return(e) => &mut e.attrs,
+        Expr::Struct(e) => &mut e.attrs,
+        Expr::Try(e) => &mut e.attrs,
+        Expr::TryBlock(e) => &mut e.attrs,
+        Expr::Tuple(e) => &mut e.attrs,
+        Expr::Unary(e) => &mut e.attrs,
+        Expr::Unsafe(e) => &mut e.attrs,
+        Expr::While(e) => &mut e.attrs,
+        Expr::Yield(e) => &mut e.attrs,
+        _ => return None,
     };
+    Some(attrs)
 }
 
-macro_rules! punct {
-    ($ch:expr, $spacing:expr) => {{
-        let _tt: TokenTree = ::proc_macro::Punct::new($ch, $spacing).into();
-        _tt
-    }};
+/// Check whether a statement is marked `#[allow_alloc]`, removing the attribute
+fn is_allowed(stmt: &mut Stmt) -> bool {
+    match stmt {
+        Stmt::Local(local) => take_allow_attr(&mut local.attrs),
+        Stmt::Macro(mac) => take_allow_attr(&mut mac.attrs),
+        Stmt::Expr(expr, _) => match expr_attrs(expr) {
+            Some(attrs) => take_allow_attr(attrs),
+            None => false,
+        },
+        Stmt::Item(_) => false,
+    }
 }
 
-macro_rules! token_stream {
-    ($($tt:expr,)*) => {
-        {
-            let _v: Vec<::proc_macro::TokenTree> = vec![$($tt),*];
-            let _ts: TokenStream = ::proc_macro::TokenStream::from_iter(_v.into_iter());
-            _ts
+/// Finds an `.await` that suspends the statement being visited, skipping over
+/// async blocks and closures since they're run separately
+struct FindAwait(Option<Error>);
+
+impl<'ast> Visit<'ast> for FindAwait {
+    fn visit_expr_await(&mut self, expr: &'ast ExprAwait) {
+        if self.0.is_none() {
+            self.0 = Some(Error::new_spanned(
+                expr,
+                "#[allow_alloc] cannot be used on statements that `.await`, \
+                 since allocations would stay allowed while the future is suspended",
+            ));
         }
-    };
-    ($($tt:expr),*) => {
-        {
-            let _v: Vec<::proc_macro::TokenTree> = vec![$($tt),*];
-            let _ts: TokenStream = ::proc_macro::TokenStream::from_iter(_v.into_iter());
-            _ts
+    }
+
+    fn visit_expr_async(&mut self, _: &'ast ExprAsync) {}
+
+    fn visit_expr_closure(&mut self, _: &'ast ExprClosure) {}
+
+    fn visit_item(&mut self, _: &'ast Item) {}
+}
+
+/// Allows allocations for any statements marked with `#[allow_alloc]`,
+/// in the function body as well as any blocks nested inside of it.
+struct AllowStatements;
+
+impl VisitMut for AllowStatements {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        visit_mut::visit_block_mut(self, block);
+
+        let allow: Stmt = syn::parse_quote! {
+            let __qadapt_allow = ::qadapt::allow_allocations();
+        };
+        let count = block.stmts.len();
+        let mut stmts = Vec::with_capacity(count);
+        for (i, mut stmt) in block.stmts.drain(..).enumerate() {
+            if !is_allowed(&mut stmt) {
+                stmts.push(stmt);
+                continue;
+            }
+
+            let mut find_await = FindAwait(None);
+            find_await.visit_stmt(&stmt);
+            if let Some(err) = find_await.0 {
+                let err = err.to_compile_error();
+                stmts.push(syn::parse_quote!(#err;));
+                stmts.push(stmt);
+                continue;
+            }
+
+            match stmt {
+                // Tail expression of the block; keep the value, but make sure
+                // any temporaries are cleaned up while allocations are allowed
+                Stmt::Expr(expr, None) if i == count - 1 => stmts.push(Stmt::Expr(
+                    syn::parse_quote! {{
+                        #allow
+                        let __ret__ = #expr;
+                        __ret__
+                    }},
+                    None,
+                )),
+                // `let` bindings have to stay in the enclosing block so that
+                // they're still visible afterward
+                Stmt::Local(_) => {
+                    stmts.push(allow.clone());
+                    stmts.push(stmt);
+                    stmts.push(syn::parse_quote! {
+                        ::std::mem::drop(__qadapt_allow);
+                    });
+                }
+                stmt => stmts.push(Stmt::Expr(
+                    syn::parse_quote! {{
+                        #allow
+                        #stmt
+                    }},
+                    None,
+                )),
+            }
         }
+        block.stmts = stmts;
+    }
+}
+
+/// Check whether a function returns `impl Future<Output = T>`
+fn returns_future(output: &ReturnType) -> bool {
+    let bounds = match output {
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::ImplTrait(ty) => &ty.bounds,
+            _ => return false,
+        },
+        ReturnType::Default => return false,
     };
+    bounds.iter().any(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(t) => t.path.segments.last(),
+            _ => None,
+        };
+        match segment {
+            Some(s) if s.ident == "Future" => match &s.arguments {
+                PathArguments::AngleBracketed(args) => args
+                    .args
+                    .iter()
+                    .any(|arg| matches!(arg, GenericArgument::AssocType(a) if a.ident == "Output")),
+                _ => false,
+            },
+            _ => false,
+        }
+    })
 }
 
-/// Generate the body of a function that is protected from making allocations.
-/// The code is conditionally compiled so that all QADAPT-related bits
-/// will be removed for release/bench builds, making the proc_macro safe
-/// to leave on in production.
-#[rustfmt::skip]
-fn protected_body(fn_body: Group) -> TokenTree {
-    group!(Delimiter::Brace, token_stream!(
-        group!(Delimiter::Brace, token_stream!(
-            punct!(':', Spacing::Joint),
-            punct!(':', Spacing::Alone),
-            ident!("qadapt"),
-            punct!(':', Spacing::Joint),
-            punct!(':', Spacing::Alone),
-            ident!("enter_protected"),
-            group!(Delimiter::Parenthesis)
-        )),
-        ident!("let"),
-        ident!("__ret__"),
-        punct!('=', Spacing::Alone),
-        fn_body.into(),
-        punct!(';', Spacing::Alone),
-        punct!('#', Spacing::Alone),
-        // When `return` statements are involved, this code can get marked as
-        // unreachable because of early exit
-        group!(Delimiter::Bracket, token_stream!(
-            ident!("allow"),
-            group!(Delimiter::Parenthesis, token_stream!(
-                ident!("unreachable_code")
+/// How the functions an attribute is applied to get protected
+struct Protect<'a> {
+    /// QADAPT function that enters a protected region, given the function's path,
+    /// a [`region_slot`] and `args`
+    guard: &'a str,
+    /// QADAPT function that wraps the future returned by `async fn`s and functions
+    /// returning `impl Future`, so that every poll is protected. It's given the
+    /// function's path, a [`region_slot`], `args` and the future.
+    poll: Option<&'a str>,
+    /// Arguments to pass along after the function's path and counter slot
+    args: TokenStream2,
+}
+
+impl Protect<'_> {
+    /// Replace the body of a function with one that runs inside a protected region.
+    /// `name` is the function's path inside the current module, and `span` is where
+    /// the region is reported to have been entered.
+    fn function(&self, name: &str, sig: &Signature, block: &mut Block, span: Span) {
+        AllowStatements.visit_block_mut(block);
+        let path = fn_path(name, sig.ident.span());
+        let slot = region_slot();
+        let args = &self.args;
+        let args = quote!(#slot, #args);
+        let guard = protect_call(self.guard, span, quote!(#path, #args));
+        let body = match self.poll {
+            // The body of an `async fn` only runs once the future is polled
+            Some(poll) if sig.asyncness.is_some() => {
+                let future = protect_call(poll, span, quote!(#path, #args, async move #block));
+                quote!({ #future.await })
+            }
+            // Keep creating the future protected as well. The body runs in a closure
+            // so that every `return` in it gives back the future being wrapped.
+            Some(poll) if returns_future(&sig.output) => {
+                let body = protected_body(guard, block);
+                let future = protect_call(poll, span, quote!(#path, #args, __qadapt_future));
+                quote!({
+                    #[allow(clippy::redundant_closure_call)]
+                    let __qadapt_future = (|| #body)();
+                    #future
+                })
+            }
+            _ => protected_body(guard, block),
+        };
+        *block = syn::parse_quote!(#body);
+    }
+}
+
+/// Protect every method in a `#[no_alloc]` impl block, other than `const fn`s and
+/// the ones that are exempt
+fn no_alloc_impl(item: &mut ItemImpl, args: &NoAllocArgs) -> Result<(), Error> {
+    let ty = type_name(&item.self_ty);
+    for impl_item in &mut item.items {
+        if let ImplItem::Fn(method) = impl_item {
+            if let Some(args) = args.fn_args(&mut method.attrs, &method.sig)? {
+                let name = format!("{}::{}", ty, method.sig.ident);
+                let span = method.sig.ident.span();
+                args.protect()
+                    .function(&name, &method.sig, &mut method.block, span);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Protect every function in a `#[no_alloc]` module, including the methods of any
+/// impl blocks and the contents of nested modules
+fn no_alloc_mod(item: &mut ItemMod, args: &NoAllocArgs) -> Result<(), Error> {
+    let items = match &mut item.content {
+        Some((_, items)) => items,
+        None => {
+            return Err(Error::new_spanned(
+                &*item,
+                "#[no_alloc] can only be used on modules declared inline",
             ))
-        )),
-        group!(Delimiter::Brace, token_stream!(
-            group!(Delimiter::Brace, token_stream!(
-                punct!(':', Spacing::Joint),
-                punct!(':', Spacing::Alone),
-                ident!("qadapt"),
-                punct!(':', Spacing::Joint),
-                punct!(':', Spacing::Alone),
-                ident!("exit_protected"),
-                group!(Delimiter::Parenthesis)
-            )),
-            ident!("__ret__")
-        ))
-    ))
-}
-
-/// Walk through a TokenStream (typically from a Group Brace) and prepend calls
-/// to `return` with an exit guard.
-fn escape_return(ts: TokenStream) -> TokenStream {
-    let mut protected: Vec<TokenTree> = Vec::new();
-    let mut in_closure: bool = false;
-
-    let mut tt_iter = ts.into_iter();
-    while let Some(tt) = tt_iter.next() {
-        let tokens = match tt {
-            TokenTree::Group(ref g) if g.delimiter() == Delimiter::Brace && !in_closure => {
-                vec![group!(Delimiter::Brace, escape_return(g.stream()))]
+        }
+    };
+    for item in items {
+        match item {
+            Item::Fn(f) => {
+                if let Some(args) = args.fn_args(&mut f.attrs, &f.sig)? {
+                    let name = f.sig.ident.to_string();
+                    let span = f.sig.ident.span();
+                    args.protect().function(&name, &f.sig, &mut f.block, span);
+                }
             }
-            TokenTree::Ident(ref i) if i.to_string() == "return" && !in_closure => vec![
-                group!(
-                    Delimiter::Brace,
-                    token_stream!(
-                        punct!(':', Spacing::Joint),
-                        punct!(':', Spacing::Alone),
-                        ident!("qadapt"),
-                        punct!(':', Spacing::Joint),
-                        punct!(':', Spacing::Alone),
-                        ident!("exit_protected"),
-                        group!(Delimiter::Parenthesis)
-                    )
-                ),
-                tt.clone(),
-            ],
-            TokenTree::Punct(ref p) if p.as_char() == '|' => {
-                in_closure = true;
-                vec![tt.clone()]
+            Item::Impl(i) => {
+                if let Some(args) = args.items_args(&mut i.attrs)? {
+                    no_alloc_impl(i, &args)?;
+                }
             }
-            TokenTree::Punct(ref p) if p.as_char() == ';' => {
-                in_closure = false;
-                vec![tt.clone()]
+            Item::Mod(m) => {
+                if let Some(args) = args.items_args(&mut m.attrs)? {
+                    no_alloc_mod(m, &args)?;
+                }
             }
-            t => vec![t],
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Violation policies that can be given to `#[no_alloc(policy = "...")]`
+const POLICIES: [(&str, &str); 6] = [
+    ("panic", "Panic"),
+    ("abort", "Abort"),
+    ("log", "Log"),
+    ("trap", "Trap"),
+    ("count", "Count"),
+    ("ignore", "Ignore"),
+];
+
+/// Arguments given to `#[no_alloc]`
+#[derive(Clone, Default)]
+struct NoAllocArgs {
+    /// `skip`: leave a function inside a `#[no_alloc]` impl block or module unprotected
+    skip: Option<Span>,
+    /// `except(name, ...)`: functions inside an impl block or module to leave unprotected
+    except: Vec<Ident>,
+    /// `allow_alloc`: allocations are fine, but freeing memory isn't
+    allow_alloc: Option<Span>,
+    /// `allow_dealloc`: freeing memory is fine, but allocations aren't
+    allow_dealloc: Option<Span>,
+    /// `policy = "..."`: the `ViolationPolicy` variant to handle violations with
+    policy: Option<Ident>,
+    /// `name = "..."`: the region's name, instead of the function's path
+    name: Option<LitStr>,
+}
+
+impl NoAllocArgs {
+    fn parse(attr: TokenStream2) -> Result<NoAllocArgs, Error> {
+        let mut args = NoAllocArgs::default();
+        let parser = syn::meta::parser(|meta| {
+            let duplicate = |is_set: bool| {
+                if is_set {
+                    Err(meta.error("Duplicate #[no_alloc] argument"))
+                } else {
+                    Ok(())
+                }
+            };
+            if meta.path.is_ident("skip") {
+                duplicate(args.skip.is_some())?;
+                args.skip = Some(meta.path.span());
+            } else if meta.path.is_ident("except") {
+                meta.parse_nested_meta(|name| {
+                    args.except.push(name.path.require_ident()?.clone());
+                    Ok(())
+                })?;
+            } else if meta.path.is_ident("allow_alloc") {
+                duplicate(args.allow_alloc.is_some())?;
+                args.allow_alloc = Some(meta.path.span());
+            } else if meta.path.is_ident("allow_dealloc") {
+                duplicate(args.allow_dealloc.is_some())?;
+                args.allow_dealloc = Some(meta.path.span());
+            } else if meta.path.is_ident("policy") {
+                duplicate(args.policy.is_some())?;
+                let value: LitStr = meta.value()?.parse()?;
+                let policy = POLICIES.iter().find(|(name, _)| value.value() == *name);
+                match policy {
+                    Some((_, variant)) => args.policy = Some(Ident::new(variant, value.span())),
+                    None => {
+                        return Err(Error::new_spanned(
+                            value,
+                            "Unknown violation policy; expected one of \
+                             \"panic\", \"abort\", \"log\", \"trap\", \"count\" or \"ignore\"",
+                        ))
+                    }
+                }
+            } else if meta.path.is_ident("name") {
+                duplicate(args.name.is_some())?;
+                args.name = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error(format!(
+                    "Unknown #[no_alloc] argument `{}`; expected `skip`, `except(...)`, \
+                     `allow_alloc`, `allow_dealloc`, `policy = \"...\"` or `name = \"...\"`",
+                    meta.path.to_token_stream()
+                )));
+            }
+            Ok(())
+        });
+        parser.parse2(attr)?;
+        args.check_allowed()?;
+        Ok(args)
+    }
+
+    fn check_allowed(&self) -> Result<(), Error> {
+        match (self.allow_alloc, self.allow_dealloc) {
+            (Some(_), Some(span)) => Err(Error::new(
+                span,
+                "`allow_alloc` and `allow_dealloc` can't be used together; \
+                 remove #[no_alloc] to allow all allocations",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Report arguments that only make sense on impl blocks and modules
+    fn for_fn(&self) -> Result<(), Error> {
+        match self.except.first() {
+            Some(name) => Err(Error::new_spanned(
+                name,
+                "`except` can only be used on impl blocks and modules",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Report arguments that only make sense on functions
+    fn for_items(&self) -> Result<(), Error> {
+        match &self.name {
+            Some(name) => Err(Error::new_spanned(
+                name,
+                "`name` can only be used on functions; use it on the functions inside instead",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Combine the arguments of a `#[no_alloc]` impl block or module with the ones
+    /// given to an item inside it, which take priority
+    fn merge(&self, inner: NoAllocArgs) -> Result<NoAllocArgs, Error> {
+        let mut except = self.except.clone();
+        except.extend(inner.except);
+        let merged = NoAllocArgs {
+            skip: inner.skip.or(self.skip),
+            except,
+            allow_alloc: inner.allow_alloc.or(self.allow_alloc),
+            allow_dealloc: inner.allow_dealloc.or(self.allow_dealloc),
+            policy: inner.policy.or_else(|| self.policy.clone()),
+            name: inner.name.or_else(|| self.name.clone()),
         };
+        merged.check_allowed()?;
+        Ok(merged)
+    }
 
-        protected.extend(tokens.into_iter());
+    /// Get the arguments for a function inside a `#[no_alloc]` impl block or module,
+    /// or `None` if it should be left alone
+    fn fn_args(
+        &self,
+        attrs: &mut Vec<Attribute>,
+        sig: &Signature,
+    ) -> Result<Option<NoAllocArgs>, Error> {
+        let inner = inner_args(attrs)?;
+        inner.for_fn()?;
+        let args = self.merge(inner)?;
+        // `const fn`s can't allocate, and can't call into QADAPT either
+        if args.skip.is_some() || sig.constness.is_some() || args.except.contains(&sig.ident) {
+            return Ok(None);
+        }
+        Ok(Some(args))
+    }
+
+    /// Get the arguments for an impl block or module nested in a `#[no_alloc]` module,
+    /// or `None` if it's skipped entirely
+    fn items_args(&self, attrs: &mut Vec<Attribute>) -> Result<Option<NoAllocArgs>, Error> {
+        let inner = inner_args(attrs)?;
+        inner.for_items()?;
+        let args = self.merge(inner)?;
+        Ok(if args.skip.is_some() {
+            None
+        } else {
+            Some(args)
+        })
     }
 
-    TokenStream::from_iter(protected.into_iter())
+    fn protect(&self) -> Protect<'static> {
+        let name = option_value(self.name.as_ref());
+        let policy = option_value(
+            self.policy
+                .as_ref()
+                .map(|p| quote!(::qadapt::ViolationPolicy::#p)),
+        );
+        let allow_alloc = self.allow_alloc.is_some();
+        let allow_dealloc = self.allow_dealloc.is_some();
+        Protect {
+            guard: "__no_alloc",
+            poll: Some("__no_alloc_future"),
+            args: quote!(#name, #policy, #allow_alloc, #allow_dealloc),
+        }
+    }
+}
+
+/// Remove any `#[no_alloc]` attributes from an item inside a `#[no_alloc]` impl block
+/// or module, since the item is already covered, and return their arguments
+fn inner_args(attrs: &mut Vec<Attribute>) -> Result<NoAllocArgs, Error> {
+    let mut args = NoAllocArgs::default();
+    let mut result = Ok(());
+    attrs.retain(|attr| {
+        let is_no_alloc = matches!(
+            attr.path().segments.last(),
+            Some(segment) if segment.ident == "no_alloc"
+        );
+        if !is_no_alloc || result.is_err() {
+            return !is_no_alloc;
+        }
+
+        let tokens = match &attr.meta {
+            Meta::Path(_) => TokenStream2::new(),
+            Meta::List(list) => list.tokens.clone(),
+            Meta::NameValue(nv) => nv.value.to_token_stream(),
+        };
+        match NoAllocArgs::parse(tokens).and_then(|inner| args.merge(inner)) {
+            Ok(merged) => args = merged,
+            Err(err) => result = Err(err),
+        }
+        false
+    });
+    result.map(|()| args)
+}
+
+/// Name of the type an impl block is for, as used in the path of its methods
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Path(ty) => match ty.path.segments.last() {
+            Some(segment) => segment.ident.to_string(),
+            None => ty.to_token_stream().to_string(),
+        },
+        _ => ty.to_token_stream().to_string(),
+    }
+}
+
+/// Replace the body of a function with one that runs inside a protected region.
+/// Anything other than a function with a body is reported as an error.
+fn protect_fn(attr_name: &str, protect: &Protect, item: TokenStream) -> TokenStream {
+    let mut item = match syn::parse::<Item>(item) {
+        Ok(Item::Fn(item)) => item,
+        Ok(item) => {
+            let err = Error::new_spanned(
+                &item,
+                format!("#[{}] can only be used on functions", attr_name),
+            );
+            return with_error(err, item);
+        }
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    if let Err(err) = not_const(attr_name, &item.sig) {
+        return with_error(err, item);
+    }
+    let name = item.sig.ident.to_string();
+    protect.function(&name, &item.sig, &mut item.block, Span::call_site());
+    item.into_token_stream().into()
+}
+
+/// Protected regions can't be entered at compile time, so `const fn`s can't be protected
+fn not_const(attr_name: &str, sig: &Signature) -> Result<(), Error> {
+    match &sig.constness {
+        Some(constness) => Err(Error::new_spanned(
+            constness,
+            format!("#[{}] cannot be used on const fn", attr_name),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Emit a compile error, along with the original item so that its uses
+/// don't report errors of their own
+fn with_error(err: Error, item: impl ToTokens) -> TokenStream {
+    let err = err.to_compile_error();
+    quote!(#err #item).into()
 }
 
 /// Set up the QADAPT allocator to trigger a panic if any allocations happen during
 /// calls to this function.
 ///
+/// Individual statements can be allowed to allocate by marking them
+/// with `#[allow_alloc]`:
+///
+/// ```rust,ignore
+/// #[no_alloc]
+/// fn checked_div(x: u32, y: u32) -> Result<u32, String> {
+///     if y == 0 {
+///         #[allow_alloc]
+///         return Err(format!("Can't divide {} by zero", x));
+///     }
+///     Ok(x / y)
+/// }
+/// ```
+///
+/// In `async` code, statements marked `#[allow_alloc]` can't `.await`; allocations
+/// would otherwise stay allowed while the future is suspended.
+///
+/// The protected region is named after the function's path, like `my_crate::engine::tick`.
+///
+/// The attribute also takes options for the region:
+///
+/// - `allow_dealloc`: freeing memory is fine, like dropping a buffer that was passed in
+/// - `allow_alloc`: allocating (and reallocating) is fine, but freeing memory isn't
+/// - `policy = "..."`: handle violations with a different `ViolationPolicy`; one of
+///   `"panic"`, `"abort"`, `"log"`, `"trap"`, `"count"` or `"ignore"`
+/// - `name = "..."`: name the region, instead of using the function's path
+///
+/// The options only apply to the function's own region. When it's called from
+/// another `#[no_alloc]` function, the caller's rules still apply as well;
+/// only `allow_alloc!` and `#[allow_alloc]` lift them.
+///
+/// ```rust,ignore
+/// #[no_alloc(allow_dealloc, policy = "log", name = "audio_callback")]
+/// fn process(output: &mut [f32], old_buffer: Vec<f32>) {
+///     drop(old_buffer);
+///     output.fill(0.0);
+/// }
+/// ```
+///
+/// On an `async fn`, or a function returning `impl Future<Output = T>`, every
+/// `poll` of the returned future runs inside the protected region, while time spent
+/// waiting to be woken up doesn't. Creating the future is protected as well.
+/// The return type has to name the trait `Future`, rather than an alias for it.
+///
+/// `#[no_alloc]` can also be put on an `impl` block (including trait impls) or an
+/// inline `mod` to protect every function inside it, other than `const fn`s.
+/// Functions are opted out individually with `#[no_alloc(skip)]`, or by name with
+/// `except(...)`, which is handy for constructors. Options given to the block apply
+/// to every function inside it, and can be overridden with `#[no_alloc(...)]` on
+/// a function:
+///
+/// ```rust,ignore
+/// #[no_alloc(except(new))]
+/// impl Engine {
+///     fn new() -> Engine {
+///         Engine { samples: Vec::with_capacity(1024) }
+///     }
+///
+///     fn tick(&mut self) -> u32 {
+///         self.samples.iter().sum()
+///     }
+///
+///     #[no_alloc(skip)]
+///     fn resize(&mut self, len: usize) {
+///         self.samples.resize(len, 0);
+///     }
+/// }
+/// ```
+///
+/// Methods are named `my_crate::engine::Engine::tick`, and violations point at the
+/// method rather than the attribute.
+///
 /// QADAPT will only track allocations in the current function call;
 /// if (for example) this function receives the results of an allocation in a
-/// separate thread, or defers allocations via closure/Future, those results
-/// will not trigger an error.
+/// separate thread, or defers allocations via a closure or a future other than
+/// the one it returns, those results will not trigger an error.
 #[proc_macro_attribute]
 #[deprecated(
     since = "1.0.3",
     note = "Please use the `alloc_counter` crate instead."
 )]
-pub fn no_alloc(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let mut protected_fn: Vec<TokenTree> = Vec::new();
-    let mut item_iter = item.into_iter();
-
-    // First, get the function body we're replicating
-    let mut fn_body = None;
-    while let Some(tt) = item_iter.next() {
-        match tt {
-            TokenTree::Group(ref g) if g.delimiter() == Delimiter::Brace => {
-                fn_body = Some(Group::new(Delimiter::Brace, escape_return(g.stream())));
-                break;
-            }
-            tt => {
-                protected_fn.push(tt.clone());
-            }
-        }
+pub fn no_alloc(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = match NoAllocArgs::parse(attr.into()) {
+        Ok(args) => args,
+        Err(err) => return with_error(err, TokenStream2::from(item)),
+    };
+    let mut item = match syn::parse::<Item>(item) {
+        Ok(item) => item,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let result = match (&mut item, args.skip) {
+        (_, Some(skip)) => Err(Error::new(
+            skip,
+            "`skip` can only be used inside a #[no_alloc] impl block or module",
+        )),
+        (Item::Fn(f), None) => args
+            .for_fn()
+            .and_then(|()| not_const("no_alloc", &f.sig))
+            .map(|()| {
+                let name = f.sig.ident.to_string();
+                args.protect()
+                    .function(&name, &f.sig, &mut f.block, Span::call_site())
+            }),
+        (Item::Impl(i), None) => args.for_items().and_then(|()| no_alloc_impl(i, &args)),
+        (Item::Mod(m), None) => args.for_items().and_then(|()| no_alloc_mod(m, &args)),
+        (item, None) => Err(Error::new_spanned(
+            item,
+            "#[no_alloc] can only be used on functions, impl blocks and modules",
+        )),
+    };
+
+    match result {
+        Ok(()) => item.into_token_stream().into(),
+        Err(err) => with_error(err, item),
     }
+}
+
+/// Parse the `count = N, bytes = M` arguments given to `#[alloc_budget]`
+fn budget_args(attr: TokenStream) -> Result<(Option<Expr>, Option<Expr>), Error> {
+    let args = Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse(attr)?;
 
-    protected_fn.push(protected_body(fn_body.as_ref().unwrap().clone()));
+    let mut count = None;
+    let mut bytes = None;
+    for arg in args {
+        let slot = if arg.path.is_ident("count") {
+            &mut count
+        } else if arg.path.is_ident("bytes") {
+            &mut bytes
+        } else {
+            return Err(Error::new_spanned(
+                &arg.path,
+                format!(
+                    "Unknown #[alloc_budget] argument `{}`; expected `count` or `bytes`",
+                    arg.path.to_token_stream()
+                ),
+            ));
+        };
+        if slot.is_some() {
+            return Err(Error::new_spanned(
+                &arg.path,
+                "Duplicate #[alloc_budget] argument",
+            ));
+        }
+        *slot = Some(arg.value);
+    }
 
-    while let Some(tt) = item_iter.next() {
-        protected_fn.push(tt)
+    if count.is_none() && bytes.is_none() {
+        return Err(Error::new(
+            Span::call_site(),
+            "#[alloc_budget] requires a `count` or `bytes` limit",
+        ));
     }
+    Ok((count, bytes))
+}
 
-    TokenStream::from_iter(protected_fn.into_iter())
+/// Set up the QADAPT allocator to allow a limited number of allocations during
+/// calls to this function, and trigger a panic once the budget is exceeded.
+///
+/// The budget is given as `count = N` (the number of allocations) and/or
+/// `bytes = M` (the total size of allocations). Freeing memory is always allowed.
+///
+/// ```rust,ignore
+/// #[alloc_budget(count = 2, bytes = 64)]
+/// fn build_small_vec() -> Vec<u8> {
+///     Vec::with_capacity(32)
+/// }
+/// ```
+///
+/// The same caveats as `#[no_alloc]` apply; only allocations made directly
+/// by the current function call are tracked.
+#[proc_macro_attribute]
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn alloc_budget(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let (count, bytes) = match budget_args(attr) {
+        Ok(args) => args,
+        Err(err) => return with_error(err, TokenStream2::from(item)),
+    };
+
+    let count = option_value(count.as_ref());
+    let bytes = option_value(bytes.as_ref());
+    let protect = Protect {
+        guard: "__alloc_budget",
+        poll: None,
+        args: quote!(#count, #bytes),
+    };
+    protect_fn("alloc_budget", &protect, item)
 }
@@ -0,0 +1,150 @@
+//! Settings read from the environment, so QADAPT's behavior can be changed
+//! per invocation without recompiling
+
+use crate::policy;
+use crate::set_immediate_panic;
+use crate::set_violation_policy;
+use crate::ViolationPolicy;
+use std::ffi::CStr;
+use std::str;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static BACKTRACES: AtomicBool = AtomicBool::new(true);
+static TRAP_VIOLATIONS: AtomicBool = AtomicBool::new(false);
+
+/// Read the `QADAPT_*` environment variables. Called once, when the first
+/// allocation is handled; nothing in here may allocate.
+pub(crate) fn load() {
+    if let Some(mode) = var("QADAPT_MODE\0") {
+        match parse_policy(mode) {
+            Some(policy) => {
+                set_violation_policy(policy);
+                if policy == ViolationPolicy::Log {
+                    policy::fail_at_exit();
+                }
+            }
+            None => unknown_value("QADAPT_MODE\0", mode),
+        }
+    }
+    if let Some(immediate) = flag("QADAPT_IMMEDIATE\0") {
+        set_immediate_panic(immediate);
+    }
+    if let Some(backtraces) = flag("QADAPT_BACKTRACE\0") {
+        BACKTRACES.store(backtraces, Ordering::Relaxed);
+    }
+    if let Some(trap) = flag("QADAPT_TRAP\0") {
+        TRAP_VIOLATIONS.store(trap, Ordering::Relaxed);
+    }
+    if let Some(disabled) = flag("QADAPT_DISABLE\0") {
+        DISABLED.store(disabled, Ordering::Relaxed);
+    }
+    open_report_file();
+}
+
+/// Whether `QADAPT_DISABLE` turned off all checks
+pub(crate) fn disabled() -> bool {
+    DISABLED.load(Ordering::Relaxed)
+}
+
+/// Whether violations should record a backtrace; `QADAPT_BACKTRACE=0` skips
+/// the capture even when the `backtrace` feature is enabled
+pub(crate) fn backtraces() -> bool {
+    BACKTRACES.load(Ordering::Relaxed)
+}
+
+/// Whether `QADAPT_TRAP` asks for every reported violation to stop in the debugger
+pub(crate) fn trap_violations() -> bool {
+    TRAP_VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Get the value of an environment variable; `name` must be nul-terminated
+fn var(name: &str) -> Option<&'static [u8]> {
+    // Safety: `name` is a valid C string. The value is only used while loading,
+    // before the program has had a chance to change its environment.
+    unsafe {
+        let value = libc::getenv(name.as_ptr() as *const libc::c_char);
+        if value.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(value).to_bytes())
+        }
+    }
+}
+
+/// Get the value of an on/off environment variable
+fn flag(name: &str) -> Option<bool> {
+    let value = var(name)?;
+    let is_any = |options: &[&str]| {
+        options
+            .iter()
+            .any(|o| value.eq_ignore_ascii_case(o.as_bytes()))
+    };
+    if is_any(&["1", "true", "yes", "on"]) {
+        Some(true)
+    } else if is_any(&["", "0", "false", "no", "off"]) {
+        Some(false)
+    } else {
+        unknown_value(name, value);
+        None
+    }
+}
+
+fn parse_policy(value: &[u8]) -> Option<ViolationPolicy> {
+    const POLICIES: [(&str, ViolationPolicy); 6] = [
+        ("panic", ViolationPolicy::Panic),
+        ("abort", ViolationPolicy::Abort),
+        ("log", ViolationPolicy::Log),
+        ("trap", ViolationPolicy::Trap),
+        ("count", ViolationPolicy::Count),
+        ("ignore", ViolationPolicy::Ignore),
+    ];
+    POLICIES
+        .iter()
+        .find(|(name, _)| value.eq_ignore_ascii_case(name.as_bytes()))
+        .map(|(_, policy)| *policy)
+}
+
+fn unknown_value(name: &str, value: &[u8]) {
+    policy::log_line(format_args!(
+        "ignoring unknown {} value {:?}",
+        name.trim_end_matches('\0'),
+        str::from_utf8(value).unwrap_or("<invalid UTF-8>")
+    ));
+}
+
+/// Send reports to the file named by `QADAPT_REPORT`, appending to it
+#[cfg(unix)]
+fn open_report_file() {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    let path = match var("QADAPT_REPORT\0") {
+        Some(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    // Safety: `path` points into the environment, so it's still nul-terminated
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND | libc::O_CLOEXEC,
+            0o644,
+        )
+    };
+    if fd < 0 {
+        policy::log_line(format_args!(
+            "unable to open QADAPT_REPORT file {:?}",
+            str::from_utf8(path).unwrap_or("<invalid UTF-8>")
+        ));
+        return;
+    }
+    policy::set_report_file(unsafe { File::from_raw_fd(fd) });
+}
+
+#[cfg(not(unix))]
+fn open_report_file() {
+    if var("QADAPT_REPORT\0").is_some() {
+        policy::log_line(format_args!("QADAPT_REPORT is only supported on Unix"));
+    }
+}
@@ -0,0 +1,303 @@
+//! Allocation counters for named protected regions. Unlike the protection checks,
+//! these are kept in release builds, so they can be exported as production metrics.
+
+use crate::AllocStats;
+use spin::RwLock;
+use std::cell::Cell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Maximum number of region names that allocations are counted for
+const MAX_COUNTED_REGIONS: usize = 64;
+/// Maximum number of nested regions tracked per thread; deeper regions aren't counted
+const MAX_DEPTH: usize = 16;
+
+static NAMES: RwLock<[Option<&'static str>; MAX_COUNTED_REGIONS]> =
+    RwLock::new([None; MAX_COUNTED_REGIONS]);
+static COUNTERS: [RegionCounters; MAX_COUNTED_REGIONS] =
+    [RegionCounters::ZERO; MAX_COUNTED_REGIONS];
+
+thread_local! {
+    static COUNTED: Cell<CountedRegions> = const { Cell::new(CountedRegions::EMPTY) };
+}
+
+struct RegionCounters {
+    allocations: AtomicUsize,
+    zeroed_allocations: AtomicUsize,
+    reallocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    bytes_allocated: AtomicUsize,
+    bytes_deallocated: AtomicUsize,
+}
+
+impl RegionCounters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: RegionCounters = RegionCounters {
+        allocations: AtomicUsize::new(0),
+        zeroed_allocations: AtomicUsize::new(0),
+        reallocations: AtomicUsize::new(0),
+        deallocations: AtomicUsize::new(0),
+        bytes_allocated: AtomicUsize::new(0),
+        bytes_deallocated: AtomicUsize::new(0),
+    };
+
+    fn add(&self, delta: &AllocStats) {
+        let add = |counter: &AtomicUsize, value: usize| {
+            if value != 0 {
+                counter.fetch_add(value, Ordering::Relaxed);
+            }
+        };
+        add(&self.allocations, delta.allocations);
+        add(&self.zeroed_allocations, delta.zeroed_allocations);
+        add(&self.reallocations, delta.reallocations);
+        add(&self.deallocations, delta.deallocations);
+        add(&self.bytes_allocated, delta.bytes_allocated);
+        add(&self.bytes_deallocated, delta.bytes_deallocated);
+    }
+
+    fn load(&self) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            zeroed_allocations: self.zeroed_allocations.load(Ordering::Relaxed),
+            reallocations: self.reallocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+            bytes_deallocated: self.bytes_deallocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Regions the current thread is in, from outermost to innermost,
+/// as indices into `COUNTERS`
+#[derive(Clone, Copy)]
+struct CountedRegions {
+    slots: [u8; MAX_DEPTH],
+    depth: usize,
+}
+
+/// Region that doesn't have its own counters
+pub(crate) const SKIP: u8 = u8::MAX - 1;
+/// Allocations are allowed; don't count them for any enclosing region
+const STOP: u8 = u8::MAX;
+/// Call site that hasn't looked up its region's counters yet
+const UNRESOLVED: u8 = u8::MAX;
+
+/// The counters for the region entered at a call site, so the region's name is only
+/// looked up the first time it's entered. `#[no_alloc]` and `#[alloc_budget]` functions
+/// keep one of these in a `static`; not public API.
+#[doc(hidden)]
+pub struct RegionSlot(AtomicU8);
+
+impl RegionSlot {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> RegionSlot {
+        RegionSlot(AtomicU8::new(UNRESOLVED))
+    }
+
+    /// Get the counters for `name`, which must be the same every time for a call site
+    pub(crate) fn get(&self, name: Option<&'static str>) -> u8 {
+        match self.0.load(Ordering::Relaxed) {
+            UNRESOLVED => {
+                let slot = lookup(name);
+                self.0.store(slot, Ordering::Relaxed);
+                slot
+            }
+            slot => slot,
+        }
+    }
+}
+
+impl CountedRegions {
+    const EMPTY: CountedRegions = CountedRegions {
+        slots: [SKIP; MAX_DEPTH],
+        depth: 0,
+    };
+
+    fn active(&self) -> &[u8] {
+        &self.slots[..self.depth.min(MAX_DEPTH)]
+    }
+}
+
+fn push(slot: u8) {
+    let _ = COUNTED.try_with(|c| {
+        let mut regions = c.get();
+        if regions.depth < MAX_DEPTH {
+            // Recursive regions are only counted once
+            let slot = if regions.active().contains(&slot) {
+                SKIP
+            } else {
+                slot
+            };
+            regions.slots[regions.depth] = slot;
+        }
+        regions.depth += 1;
+        c.set(regions);
+    });
+}
+
+/// Start counting allocations for a protected region, using the counters
+/// found by [`lookup`]
+pub(crate) fn enter(slot: u8) {
+    push(slot);
+}
+
+/// Find the counters for a region name, or [`SKIP`] if the region isn't counted
+pub(crate) fn lookup(name: Option<&'static str>) -> u8 {
+    name.and_then(slot).unwrap_or(SKIP)
+}
+
+/// Stop counting allocations for enclosing regions until the matching [`exit`]
+pub(crate) fn allow() {
+    push(STOP);
+}
+
+/// Leave the innermost region entered with [`enter`] or [`allow`]
+pub(crate) fn exit() {
+    let _ = COUNTED.try_with(|c| {
+        let mut regions = c.get();
+        regions.depth = regions.depth.saturating_sub(1);
+        c.set(regions);
+    });
+}
+
+/// Forget all regions on the current thread, after a violation panics out of them
+pub(crate) fn reset() {
+    let _ = COUNTED.try_with(|c| c.set(CountedRegions::EMPTY));
+}
+
+/// Add an allocator interaction to the counters of the regions the current thread is in.
+/// Safe to call from inside the allocator.
+pub(crate) fn record(delta: &AllocStats) {
+    let regions = COUNTED.try_with(Cell::get).unwrap_or(CountedRegions::EMPTY);
+    for &slot in regions.active().iter().rev() {
+        match slot {
+            STOP => break,
+            SKIP => (),
+            slot => COUNTERS[slot as usize].add(delta),
+        }
+    }
+}
+
+/// Find the counters for a region name, claiming an unused slot if it hasn't been seen yet
+fn slot(name: &'static str) -> Option<u8> {
+    if let Some(slot) = find(&*NAMES.read(), name) {
+        return Some(slot);
+    }
+
+    let mut names = NAMES.write();
+    if let Some(slot) = find(&*names, name) {
+        return Some(slot);
+    }
+    let slot = match names.iter().position(Option::is_none) {
+        Some(slot) => slot,
+        None => {
+            drop(names);
+            static REPORTED: AtomicBool = AtomicBool::new(false);
+            if !REPORTED.swap(true, Ordering::Relaxed) {
+                crate::policy::log_line(format_args!(
+                    "all {} region counters are in use, so allocations in region \"{}\" \
+                     and any other new regions aren't counted",
+                    MAX_COUNTED_REGIONS, name
+                ));
+            }
+            return None;
+        }
+    };
+    names[slot] = Some(name);
+    Some(slot as u8)
+}
+
+fn find(names: &[Option<&'static str>], name: &str) -> Option<u8> {
+    names
+        .iter()
+        .position(|n| *n == Some(name))
+        .map(|slot| slot as u8)
+}
+
+/// Get the allocations made inside protected regions named `name`, across all threads.
+///
+/// Counting doesn't depend on the protection checks, so it stays enabled in release builds:
+/// naming a region (or marking a function `#[no_alloc]`) is enough to get a cheap production
+/// metric for the allocations inside of it. Allocations are counted for every enclosing named
+/// region, stopping at [`allow_allocations`](crate::allow_allocations).
+///
+/// Regions entered by `#[no_alloc]` and `#[alloc_budget]` functions, `assert_no_alloc!`
+/// with a literal name, and futures look up their counters once, so entering them
+/// afterwards only costs a few thread-local and atomic updates. Regions entered with
+/// [`protect_named`](crate::protect_named) look up their name every time.
+///
+/// Only the first 64 region names are counted; regions entered with any other name
+/// are left out of the statistics, and QADAPT logs a line to stderr the first time
+/// that happens.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::protect_named;
+/// use qadapt::region_stats;
+/// use qadapt::set_violation_policy;
+/// use qadapt::ViolationPolicy;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     // Don't panic in debug builds
+///     set_violation_policy(ViolationPolicy::Count);
+///
+///     let guard = protect_named("latency_critical");
+///     let b = std::hint::black_box(Box::new(12u32));
+///     drop(b);
+///     drop(guard);
+///
+///     let stats = region_stats("latency_critical");
+///     assert_eq!(stats.allocations, 1);
+///     assert_eq!(stats.deallocations, 1);
+///     assert_eq!(stats.bytes_allocated, 4);
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn region_stats(name: &str) -> AllocStats {
+    find(&*NAMES.read(), name).map_or(AllocStats::default(), |slot| COUNTERS[slot as usize].load())
+}
+
+/// Get the allocation counters of every named region that has been entered,
+/// for exporting them all at once. See [`region_stats`].
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::all_region_stats;
+/// use qadapt::no_alloc;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// #[no_alloc]
+/// fn tick() {}
+///
+/// fn main() {
+///     tick();
+///
+///     for (name, stats) in all_region_stats() {
+///         println!("{}: {} allocations", name, stats.allocations);
+///     }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn all_region_stats() -> impl Iterator<Item = (&'static str, AllocStats)> {
+    let names = *NAMES.read();
+    IntoIterator::into_iter(names)
+        .enumerate()
+        .filter_map(|(slot, name)| Some((name?, COUNTERS[slot].load())))
+}
@@ -0,0 +1,195 @@
+//! Protection for futures and streams, where a region should only cover the time
+//! spent polling rather than the time spent waiting to be woken up.
+//!
+//! Wrapping a task with [`no_alloc`](NoAllocFutureExt::no_alloc) is a way to check
+//! that it has reached an allocation-free steady state; every `poll` is run in a
+//! protected region, and violations are reported as soon as the `poll` returns.
+//! Streams (from the `futures-core` crate) are supported when the `futures-core`
+//! feature is enabled.
+//!
+//! **Example**:
+//!
+//! ```rust
+//! use qadapt::future::NoAllocFutureExt;
+//! use qadapt::QADAPT;
+//! use std::future::Future;
+//! use std::pin::Pin;
+//! use std::task::Context;
+//! use std::task::Poll;
+//! # use std::task::RawWaker;
+//! # use std::task::RawWakerVTable;
+//! use std::task::Waker;
+//!
+//! #[global_allocator]
+//! static Q: QADAPT = QADAPT;
+//!
+//! async fn read_sample(samples: &[u32]) -> u32 {
+//!     samples.iter().sum()
+//! }
+//!
+//! # const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+//! # fn clone(_: *const ()) -> RawWaker {
+//! #     RawWaker::new(std::ptr::null(), &VTABLE)
+//! # }
+//! # fn noop(_: *const ()) {}
+//! # fn noop_waker() -> Waker {
+//! #     unsafe { Waker::from_raw(clone(std::ptr::null())) }
+//! # }
+//! fn main() {
+//!     let samples = [1, 2, 3];
+//!     let mut task = Box::pin(read_sample(&samples).no_alloc_named("read_sample"));
+//!
+//!     // Usually an executor does the polling; this waker doesn't do anything
+//!     let waker = noop_waker();
+//!     let mut cx = Context::from_waker(&waker);
+//!     assert_eq!(task.as_mut().poll(&mut cx), Poll::Ready(6));
+//! }
+//! ```
+
+use crate::enter_region;
+use crate::Origin;
+use crate::ProtectionGuard;
+use crate::Region;
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+/// Future that enters a protected region every time it's polled, and exits it
+/// again before returning. Created by [`NoAllocFutureExt`], or by `#[no_alloc]`
+/// on `async fn`s.
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[must_use = "futures do nothing unless polled"]
+pub struct NoAllocFuture<F> {
+    inner: F,
+    region: Region,
+}
+
+impl<F> NoAllocFuture<F> {
+    pub(crate) fn new(region: Region, inner: F) -> Self {
+        NoAllocFuture {
+            inner,
+            region: region.with_counter(),
+        }
+    }
+
+    /// Get the wrapped future back
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: Future> Future for NoAllocFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // Safety: `inner` is never moved out of a pinned `self`, so it stays pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        let _guard = ProtectionGuard::new(enter_region(this.region));
+        inner.poll(cx)
+    }
+}
+
+/// Adds [`no_alloc`](NoAllocFutureExt::no_alloc) to every `std::future::Future`
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub trait NoAllocFutureExt: Future + Sized {
+    /// Run every `poll` of this future inside a protected region
+    #[track_caller]
+    fn no_alloc(self) -> NoAllocFuture<Self> {
+        NoAllocFuture::new(
+            Region::at(Origin::Call("no_alloc"), Location::caller()),
+            self,
+        )
+    }
+
+    /// Run every `poll` of this future inside a protected region called `name`,
+    /// which is included in violation reports and counted by
+    /// [`region_stats`](crate::region_stats)
+    #[track_caller]
+    fn no_alloc_named(self, name: &'static str) -> NoAllocFuture<Self> {
+        let region = Region::at(Origin::Call("no_alloc_named"), Location::caller());
+        NoAllocFuture::new(region.with_name(Some(name)), self)
+    }
+}
+
+impl<F: Future> NoAllocFutureExt for F {}
+
+/// Stream that enters a protected region every time it's polled for the next item,
+/// and exits it again before returning. Created by [`NoAllocStreamExt`].
+#[cfg(feature = "futures-core")]
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[must_use = "streams do nothing unless polled"]
+pub struct NoAllocStream<S> {
+    inner: S,
+    region: Region,
+}
+
+#[cfg(feature = "futures-core")]
+impl<S> NoAllocStream<S> {
+    /// Get the wrapped stream back
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[cfg(feature = "futures-core")]
+impl<S: futures_core::Stream> futures_core::Stream for NoAllocStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        // Safety: `inner` is never moved out of a pinned `self`, so it stays pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        let _guard = ProtectionGuard::new(enter_region(this.region));
+        inner.poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Adds [`no_alloc`](NoAllocStreamExt::no_alloc) to every `futures_core::Stream`
+#[cfg(feature = "futures-core")]
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub trait NoAllocStreamExt: futures_core::Stream + Sized {
+    /// Run every `poll_next` of this stream inside a protected region
+    #[track_caller]
+    fn no_alloc(self) -> NoAllocStream<Self> {
+        NoAllocStream {
+            inner: self,
+            region: Region::at(Origin::Call("no_alloc"), Location::caller()).with_counter(),
+        }
+    }
+
+    /// Run every `poll_next` of this stream inside a protected region called `name`,
+    /// which is included in violation reports and counted by
+    /// [`region_stats`](crate::region_stats)
+    #[track_caller]
+    fn no_alloc_named(self, name: &'static str) -> NoAllocStream<Self> {
+        let region = Region::at(Origin::Call("no_alloc_named"), Location::caller());
+        NoAllocStream {
+            inner: self,
+            region: region.with_name(Some(name)).with_counter(),
+        }
+    }
+}
+
+#[cfg(feature = "futures-core")]
+impl<S: futures_core::Stream> NoAllocStreamExt for S {}
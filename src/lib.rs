@@ -9,10 +9,12 @@
 //! writing code without worrying if Rust properly managed to inline the variable into the stack.
 //!
 //! Now, an allocator blowing up in production is a scary thought; that's why QADAPT
-//! is designed to strip its own code out whenever you're running with a release build.
-//! Just like the [`debug_assert!` macro](https://doc.rust-lang.org/std/macro.debug_assert.html)
+//! turns its checks off whenever you're running with a release build. Just like the
+//! [`debug_assert!` macro](https://doc.rust-lang.org/std/macro.debug_assert.html)
 //! in Rust's standard library, it's safe to use without worrying about a unforeseen
-//! circumstance causing your application to crash.
+//! circumstance causing your application to crash. What stays behind is cheap: entering
+//! a protected region still updates a thread-local list of regions, and allocations
+//! inside named regions are added to relaxed atomic counters (see [`region_stats`]).
 //!
 //! # Usage
 //!
@@ -67,6 +69,9 @@
 //! }
 //! ```
 //!
+//! `#[no_alloc]` can also be put on `impl` blocks and inline modules to protect
+//! every function inside them.
+//!
 //! 2. Evaluate expressions with the `assert_no_alloc!` macro
 //! ```rust
 //! use qadapt::assert_no_alloc;
@@ -84,100 +89,1538 @@
 //!     assert_eq!(x, 10);
 //! }
 //! ```
+//!
+//! # Release builds
+//!
+//! QADAPT's checks are compiled out of release builds; only the region bookkeeping
+//! for the production metrics below is left. Some allocations only disappear
+//! (or only show up) once the optimizer has run, though; to check the optimized code,
+//! enable the `release-checks` feature and run your tests with `--release`:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! qadapt = { version = "1.0", features = ["release-checks"] }
+//! ```
+//!
+//! With the feature enabled, QADAPT behaves the same way in release builds as it does
+//! in debug builds, and `is_active()` returns `true`. The one exception is immediate
+//! panics: optimized code relies on the allocator not unwinding, so
+//! [`set_immediate_panic(true)`](set_immediate_panic) and `QADAPT_IMMEDIATE=1` are ignored (with a note on
+//! stderr), and violations are reported when the protected region exits.
+//!
+//! # Production metrics
+//!
+//! Allocations inside named regions (including `#[no_alloc]` functions, which are named
+//! after their path) are tallied with relaxed atomic counters. Unlike the checks, the counters
+//! are kept in release builds, so the same annotations can be exported as a metric:
+//!
+//! ```rust
+//! use qadapt::all_region_stats;
+//!
+//! fn export_metrics() {
+//!     for (region, stats) in all_region_stats() {
+//!         println!("allocations{{region=\"{}\"}} {}", region, stats.allocations);
+//!     }
+//! }
+//! ```
+//!
+//! # Async code
+//!
+//! `#[no_alloc]` works on `async fn`s as well: every `poll` of the future runs inside
+//! the protected region, while time spent suspended doesn't. Other futures can be wrapped
+//! the same way with the `qadapt::future` extension traits, which also cover streams
+//! when the `futures-core` feature is enabled:
+//!
+//! ```rust
+//! use qadapt::future::NoAllocFutureExt;
+//!
+//! async fn handle_request() {}
+//!
+//! fn spawn_handler() -> impl std::future::Future<Output = ()> {
+//!     handle_request().no_alloc_named("handle_request")
+//! }
+//! ```
+//!
+//! # Backtraces
+//!
+//! Enable the `backtrace` feature to find out where an allocation came from:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! qadapt = { version = "1.0", features = ["backtrace"] }
+//! ```
+//!
+//! QADAPT will record the stack when a violation happens, and include the resolved
+//! frames in the panic message. Violations that are logged instead of panicking
+//! print the raw frame addresses, since symbols can't be resolved from inside the allocator.
+//!
+//! # Configuration
+//!
+//! QADAPT reads a few environment variables when the program first allocates,
+//! so checks can be adjusted per run (for example, in CI) without recompiling:
+//!
+//! | Variable | Effect |
+//! | --- | --- |
+//! | `QADAPT_MODE` | Default violation policy: `panic`, `abort`, `log`, `trap`, `count` or `ignore`. With `log`, the process exits with a failure status if there were any violations |
+//! | `QADAPT_IMMEDIATE` | Set to `1` to panic at the violating allocation, like `set_immediate_panic(true)`; debug builds only |
+//! | `QADAPT_BACKTRACE` | Set to `0` to skip recording backtraces when the `backtrace` feature is enabled |
+//! | `QADAPT_REPORT` | Append violation reports to this file instead of stderr (Unix only) |
+//! | `QADAPT_TRAP` | Set to `1` to stop in the debugger at every violation; see below |
+//! | `QADAPT_DISABLE` | Set to `1` to turn off all checks; `is_active()` returns `false` |
+//!
+//! On/off variables also accept `true`/`false`, `yes`/`no` and `on`/`off`. Settings made
+//! by the program itself, like `set_violation_policy`, take precedence.
+//!
+//! # Debugging
+//!
+//! When a violation panics, unwinding destroys the context it happened in. Run the
+//! program with `QADAPT_TRAP=1` to stop in the debugger at the offending allocation instead:
+//!
+//! ```sh
+//! QADAPT_TRAP=1 gdb --args target/debug/deps/my_test-0123456789abcdef
+//! ```
+//!
+//! QADAPT calls `qadapt_violation_breakpoint` for every reported violation, which raises
+//! `SIGTRAP` on Unix; move `up` the stack from there to find the code that allocated.
+//! Continuing in the debugger handles the violation as usual. Regions can also use
+//! `ViolationPolicy::Trap` to stop in the debugger without the environment variable.
 #![deny(missing_docs)]
+// QADAPT's own items are marked deprecated; don't warn on internal uses
+#![allow(deprecated)]
+
+// Re-export the proc macros to use by other code
+pub use qadapt_macro::*;
+
+mod config;
+mod counters;
+pub mod future;
+mod policy;
+mod stats;
+mod trace;
+
+pub use crate::counters::all_region_stats;
+pub use crate::counters::region_stats;
+#[doc(hidden)]
+pub use crate::counters::RegionSlot as __RegionSlot;
+pub use crate::policy::qadapt_violation_breakpoint;
+pub use crate::policy::region_violation_count;
+pub use crate::policy::set_violation_hook;
+pub use crate::policy::set_violation_policy;
+pub use crate::policy::take_violation_hook;
+pub use crate::policy::violation_count;
+pub use crate::policy::violation_policy;
+pub use crate::policy::ViolationPolicy;
+pub use crate::stats::count_allocs;
+pub use crate::stats::AllocStats;
+
+use crate::counters::RegionSlot;
+use crate::trace::Frames;
+use spin::RwLock;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic;
+use std::panic::Location;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+thread_local! {
+    static PROTECTION_LEVEL: RwLock<usize> = const { RwLock::new(0) };
+    static REGIONS: RwLock<RegionStack> = const { RwLock::new(RegionStack::EMPTY) };
+    static PENDING_VIOLATION: RwLock<PendingViolation> = const { RwLock::new(PendingViolation::NONE) };
+    static CAPTURED_VIOLATION: RwLock<PendingViolation> = const { RwLock::new(PendingViolation::NONE) };
+    static INTERNAL_ALLOCATION: Cell<bool> = const { Cell::new(false) };
+}
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+static IMMEDIATE_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// The kind of allocator interaction that caused a violation
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A call to `alloc`
+    Allocation,
+    /// A call to `alloc_zeroed`
+    ZeroedAllocation,
+    /// A call to `realloc`
+    Reallocation {
+        /// The size that memory was resized to
+        new_size: usize,
+    },
+    /// A call to `dealloc`
+    Deallocation,
+}
+
+/// An allocator interaction that happened while in a protected region.
+///
+/// When a violation triggers a panic, this is the panic payload, so tests
+/// can check exactly what went wrong. Since the default panic hook can't
+/// display it, the violation is also written to stderr before panicking.
+/// With the `backtrace` feature, displaying the payload includes the stack
+/// the violation happened on.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::no_alloc;
+/// use qadapt::AllocationViolation;
+/// use qadapt::ViolationKind;
+/// use qadapt::QADAPT;
+/// use std::panic::catch_unwind;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// #[no_alloc]
+/// fn allocates() -> Box<u32> {
+///     Box::new(12)
+/// }
+///
+/// fn main() {
+///     let err = catch_unwind(allocates);
+///     # if qadapt::is_active() {
+///     let err = err.unwrap_err();
+///     let violation = err.downcast_ref::<AllocationViolation>().unwrap();
+///     assert_eq!(violation.kind, ViolationKind::Allocation);
+///     assert_eq!(violation.size, 4);
+///     assert_eq!(violation.region, Some("rust_out::allocates"));
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationViolation {
+    /// What the allocator was asked to do
+    pub kind: ViolationKind,
+    /// Size of the memory involved; for reallocations, the size before resizing
+    pub size: usize,
+    /// Alignment of the memory involved
+    pub align: usize,
+    /// Protection level of the thread at the time of the violation
+    pub protection_level: usize,
+    /// Name of the protected region the violation was reported for, if it has one
+    pub region: Option<&'static str>,
+    /// ID of the thread the violation happened on, as given by the `thread-id` crate
+    pub thread_id: usize,
+    /// Number of violations in the same region after this one
+    pub others: usize,
+    budget: Option<Budget>,
+    enclosing: Enclosing,
+    frames: Frames,
+    /// Whether displaying the violation resolves its backtrace; only set once
+    /// it's used as a panic payload, since resolving symbols allocates
+    resolve_frames: bool,
+}
+
+impl AllocationViolation {
+    fn new(kind: ViolationKind, layout: &Layout, protection_level: usize) -> AllocationViolation {
+        AllocationViolation {
+            kind,
+            size: layout.size(),
+            align: layout.align(),
+            protection_level,
+            region: None,
+            thread_id: 0,
+            others: 0,
+            budget: None,
+            enclosing: Enclosing::NONE,
+            frames: Frames::EMPTY,
+            resolve_frames: false,
+        }
+    }
+
+    /// Get the stack the violation happened on, with symbols resolved. Empty unless
+    /// the `backtrace` feature is enabled.
+    ///
+    /// Resolving symbols allocates, so this must not be used inside a protected region.
+    pub fn backtrace(&self) -> impl fmt::Display + '_ {
+        trace::Resolved(&self.frames)
+    }
+
+    /// Record the stack the violation happened on, if the `backtrace` feature is enabled
+    fn with_frames(self) -> AllocationViolation {
+        if !config::backtraces() {
+            return self;
+        }
+        claim_internal_alloc();
+        let frames = Frames::capture();
+        release_internal_alloc();
+        AllocationViolation { frames, ..self }
+    }
+}
+
+impl fmt::Display for AllocationViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ViolationKind::Allocation => write!(f, "Unexpected allocation for size {}", self.size)?,
+            ViolationKind::ZeroedAllocation => {
+                write!(f, "Unexpected zeroed allocation for size {}", self.size)?
+            }
+            ViolationKind::Reallocation { new_size } => write!(
+                f,
+                "Unexpected reallocation from {} to {} bytes",
+                self.size, new_size
+            )?,
+            ViolationKind::Deallocation => {
+                write!(f, "Unexpected deallocation for size {}", self.size)?
+            }
+        }
+        write!(f, "{}", self.enclosing)?;
+        write!(f, ", protection level: {}", self.protection_level)?;
+        if let Some(ref budget) = self.budget {
+            write!(f, ", {}", budget)?;
+        }
+        if self.others > 0 {
+            write!(f, " (and {} more in this region)", self.others)?;
+        }
+        if self.resolve_frames {
+            write!(f, "{}", trace::Resolved(&self.frames))?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of nested protected regions QADAPT keeps details for;
+/// regions nested any deeper share the settings of the innermost tracked region.
+const MAX_REGIONS: usize = 32;
+
+/// Maximum number of enclosing regions named when reporting a violation
+const MAX_REPORTED_REGIONS: usize = 4;
+
+/// How a protected region was entered
+#[derive(Clone, Copy, Debug)]
+enum Origin {
+    /// One of the `enter_protected`/`protect` functions
+    Call(&'static str),
+    /// The `assert_no_alloc!` macro
+    Macro(&'static str),
+    /// A function marked with one of the QADAPT attributes
+    Function {
+        attr: &'static str,
+        path: &'static str,
+    },
+    /// Placeholder for a region where allocations are allowed
+    Allowed,
+    /// Placeholder for a region that was exited before regions entered after it
+    Exited,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Origin::Call(name) => write!(f, "{}()", name),
+            Origin::Macro(name) => write!(f, "{}!", name),
+            Origin::Function { attr, path } => write!(f, "#[{}] fn {}", attr, path),
+            Origin::Allowed => write!(f, "allow_allocations()"),
+            Origin::Exited => write!(f, "(exited)"),
+        }
+    }
+}
+
+/// Where a protected region was entered, and what it's called
+#[derive(Clone, Copy, Debug)]
+struct Site {
+    origin: Origin,
+    name: Option<&'static str>,
+    location: Option<&'static Location<'static>>,
+}
+
+impl Site {
+    /// The region's name; functions are named after their path by default
+    fn name(&self) -> Option<&'static str> {
+        match self.origin {
+            Origin::Function { path, .. } => self.name.or(Some(path)),
+            _ => self.name,
+        }
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "region \"{}\"", name)?,
+            None => write!(f, "{}", self.origin)?,
+        }
+        match self.location {
+            Some(l) => write!(f, " ({}:{})", l.file(), l.line()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The regions a violation happened in, innermost first
+#[derive(Clone, Copy, Debug)]
+struct Enclosing {
+    sites: [Option<Site>; MAX_REPORTED_REGIONS],
+    more: usize,
+}
+
+impl Enclosing {
+    const NONE: Enclosing = Enclosing {
+        sites: [None; MAX_REPORTED_REGIONS],
+        more: 0,
+    };
+
+    /// Name of the innermost region, if it has one
+    fn name(&self) -> Option<&'static str> {
+        self.sites[0].and_then(|s| s.name())
+    }
+}
+
+impl fmt::Display for Enclosing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, site) in self.sites.iter().flatten().enumerate() {
+            match i {
+                0 => write!(f, " inside {}", site)?,
+                _ => write!(f, ", within {}", site)?,
+            }
+        }
+        match self.more {
+            0 => Ok(()),
+            1 => write!(f, ", within 1 more region"),
+            more => write!(f, ", within {} more regions", more),
+        }
+    }
+}
+
+/// Settings for a single protected region
+#[derive(Clone, Copy)]
+struct Region {
+    site: Site,
+    policy: Option<ViolationPolicy>,
+    budget: Option<Budget>,
+    allowed: Allowed,
+    capture: bool,
+    /// Allocation counters for the region, if they've already been looked up
+    counter: Option<u8>,
+}
+
+impl Region {
+    const ALLOWED: Region = Region {
+        site: Site {
+            origin: Origin::Allowed,
+            name: None,
+            location: None,
+        },
+        policy: None,
+        budget: None,
+        allowed: Allowed::NOTHING,
+        capture: false,
+        counter: Some(counters::SKIP),
+    };
+
+    const EXITED: Region = Region {
+        site: Site {
+            origin: Origin::Exited,
+            ..Region::ALLOWED.site
+        },
+        ..Region::ALLOWED
+    };
+
+    /// Create a region, remembering where the public entry point was called from
+    #[track_caller]
+    fn entered(origin: Origin) -> Region {
+        Region::at(origin, Location::caller())
+    }
+
+    fn at(origin: Origin, location: &'static Location<'static>) -> Region {
+        Region {
+            site: Site {
+                origin,
+                name: None,
+                location: Some(location),
+            },
+            policy: None,
+            budget: None,
+            allowed: Allowed::NOTHING,
+            capture: false,
+            counter: None,
+        }
+    }
+
+    fn with_name(mut self, name: Option<&'static str>) -> Region {
+        self.site.name = name;
+        self
+    }
+
+    /// Hold on to violations in this region and the regions inside it,
+    /// rather than handling them with the usual policy
+    fn capturing(self) -> Region {
+        Region {
+            capture: true,
+            ..self
+        }
+    }
+
+    fn with_policy(self, policy: ViolationPolicy) -> Region {
+        Region {
+            policy: Some(policy),
+            ..self
+        }
+    }
+
+    fn with_budget(self, count: Option<usize>, bytes: Option<usize>) -> Region {
+        Region {
+            budget: Some(Budget {
+                count,
+                bytes,
+                used_count: 0,
+                used_bytes: 0,
+            }),
+            ..self
+        }
+    }
+
+    fn allowing(self, allowed: Allowed) -> Region {
+        Region { allowed, ..self }
+    }
+
+    /// Use the counters cached at the call site entering this region
+    fn counted_in(self, slot: &RegionSlot) -> Region {
+        Region {
+            counter: Some(slot.get(self.site.name())),
+            ..self
+        }
+    }
+
+    /// Look up the region's counters now, rather than every time it's entered
+    fn with_counter(self) -> Region {
+        Region {
+            counter: Some(
+                self.counter
+                    .unwrap_or_else(|| counters::lookup(self.site.name())),
+            ),
+            ..self
+        }
+    }
+
+    /// Decide whether an allocator interaction breaks the rules of this region,
+    /// and what policy to handle it with
+    fn check(
+        &mut self,
+        violation: AllocationViolation,
+    ) -> Option<(AllocationViolation, Option<ViolationPolicy>)> {
+        if self.allowed.permits(violation.kind) {
+            return None;
+        }
+        let violation = match self.budget {
+            Some(ref mut budget) => budget.charge(violation)?,
+            None => violation,
+        };
+        Some((violation, self.policy))
+    }
+}
+
+/// Allocator interactions that a region doesn't consider violations at all
+#[derive(Clone, Copy, Debug)]
+struct Allowed {
+    /// Allocations, including zeroed allocations and reallocations
+    allocations: bool,
+    deallocations: bool,
+}
+
+impl Allowed {
+    const NOTHING: Allowed = Allowed {
+        allocations: false,
+        deallocations: false,
+    };
+
+    fn permits(&self, kind: ViolationKind) -> bool {
+        match kind {
+            ViolationKind::Deallocation => self.deallocations,
+            _ => self.allocations,
+        }
+    }
+}
+
+/// Allocations a region is allowed to make before they count as violations
+#[derive(Clone, Copy, Debug)]
+struct Budget {
+    count: Option<usize>,
+    bytes: Option<usize>,
+    used_count: usize,
+    used_bytes: usize,
+}
+
+impl Budget {
+    /// Charge an allocator interaction against the budget, and get back
+    /// a violation if it goes over
+    fn charge(&mut self, violation: AllocationViolation) -> Option<AllocationViolation> {
+        let bytes = match violation.kind {
+            ViolationKind::Allocation | ViolationKind::ZeroedAllocation => violation.size,
+            ViolationKind::Reallocation { new_size } => new_size,
+            // Freeing memory doesn't count against the budget
+            ViolationKind::Deallocation => return None,
+        };
+        // Charged inside the allocator, so overflow can't be allowed to panic
+        self.used_count = self.used_count.saturating_add(1);
+        self.used_bytes = self.used_bytes.saturating_add(bytes);
+
+        let over_count = matches!(self.count, Some(c) if self.used_count > c);
+        let over_bytes = matches!(self.bytes, Some(b) if self.used_bytes > b);
+        if over_count || over_bytes {
+            Some(AllocationViolation {
+                budget: Some(*self),
+                ..violation
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "allocation budget exceeded: {} allocations",
+            self.used_count
+        )?;
+        if let Some(count) = self.count {
+            write!(f, " (limit {})", count)?;
+        }
+        write!(f, ", {} bytes", self.used_bytes)?;
+        if let Some(bytes) = self.bytes {
+            write!(f, " (limit {})", bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a guard's region was pushed onto the region stack
+#[derive(Clone, Copy)]
+struct StackEntry {
+    depth: usize,
+    resets: usize,
+}
+
+struct RegionStack {
+    depth: usize,
+    /// How many times a violation has cleared the stack; guards entered
+    /// before a reset have nothing left to clean up
+    resets: usize,
+    regions: [Region; MAX_REGIONS],
+    /// The protection level saved by each region where allocations were allowed
+    suspended: [usize; MAX_REGIONS],
+}
+
+impl RegionStack {
+    const EMPTY: RegionStack = RegionStack {
+        depth: 0,
+        resets: 0,
+        regions: [Region::ALLOWED; MAX_REGIONS],
+        suspended: [0; MAX_REGIONS],
+    };
+
+    #[cfg_attr(
+        not(any(debug_assertions, feature = "release-checks")),
+        allow(dead_code)
+    )]
+    fn push(&mut self, region: Region) -> StackEntry {
+        if self.depth < MAX_REGIONS {
+            self.regions[self.depth] = region;
+        }
+        self.depth += 1;
+        StackEntry {
+            depth: self.depth,
+            resets: self.resets,
+        }
+    }
+
+    #[cfg_attr(
+        not(any(debug_assertions, feature = "release-checks")),
+        allow(dead_code)
+    )]
+    fn push_allowed(&mut self, level: usize) -> StackEntry {
+        if self.depth < MAX_REGIONS {
+            self.suspended[self.depth] = level;
+        }
+        self.push(Region::ALLOWED)
+    }
+
+    fn pop(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        // Regions that were exited out of order go along with the ones above them
+        while self.depth > 0
+            && self.depth <= MAX_REGIONS
+            && matches!(self.regions[self.depth - 1].site.origin, Origin::Exited)
+        {
+            self.depth -= 1;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.depth = 0;
+        self.resets = self.resets.wrapping_add(1);
+    }
+
+    /// Find the closest region where allocations were allowed that was
+    /// entered after the one at `entry`
+    fn allowed_above(&self, entry: StackEntry) -> Option<usize> {
+        (entry.depth..self.depth.min(MAX_REGIONS))
+            .find(|&i| matches!(self.regions[i].site.origin, Origin::Allowed))
+    }
+
+    /// Remove a guard's entry, even if regions entered after it are still on
+    /// the stack. Returns `false` if the stack was reset since the guard was created.
+    fn remove(&mut self, entry: StackEntry) -> bool {
+        if entry.resets != self.resets || entry.depth == 0 || entry.depth > self.depth {
+            return false;
+        }
+        if entry.depth < self.depth {
+            // Keep the entry's place so the regions above it stay where their
+            // guards expect them; it's dropped along with them later
+            if entry.depth <= MAX_REGIONS {
+                self.regions[entry.depth - 1] = Region::EXITED;
+            }
+            return true;
+        }
+        self.pop();
+        true
+    }
+
+    /// Remove a protected region's entry, returning whether it counted towards
+    /// the current protection level rather than a level saved by a region
+    /// where allocations were allowed
+    fn remove_protected(&mut self, entry: StackEntry) -> Option<bool> {
+        let suspended_by = self.allowed_above(entry);
+        if !self.remove(entry) {
+            return None;
+        }
+        match suspended_by {
+            Some(i) => {
+                self.suspended[i] = self.suspended[i].saturating_sub(1);
+                Some(false)
+            }
+            None => Some(true),
+        }
+    }
+
+    /// Remove an allowed region's entry, returning the protection level to
+    /// restore given the `current` one
+    fn remove_allowed(&mut self, entry: StackEntry, saved: usize, current: usize) -> Option<usize> {
+        let index = entry.depth.wrapping_sub(1);
+        let saved = if index < MAX_REGIONS {
+            self.suspended[index]
+        } else {
+            saved
+        };
+        let suspended_by = self.allowed_above(entry);
+        if !self.remove(entry) {
+            return None;
+        }
+        match suspended_by {
+            // Regions entered since were suspended in turn, so their level
+            // is the one that has to include ours
+            Some(i) => {
+                self.suspended[i] += saved;
+                Some(current)
+            }
+            None => Some(saved + current),
+        }
+    }
+
+    /// The protected regions currently being checked, innermost first,
+    /// stopping at any region where allocations were allowed
+    fn active(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.depth.min(MAX_REGIONS)]
+            .iter()
+            .rev()
+            .filter(|r| !matches!(r.site.origin, Origin::Exited))
+            .take_while(|r| !matches!(r.site.origin, Origin::Allowed))
+    }
+
+    fn active_mut(&mut self) -> impl Iterator<Item = &mut Region> {
+        self.regions[..self.depth.min(MAX_REGIONS)]
+            .iter_mut()
+            .rev()
+            .filter(|r| !matches!(r.site.origin, Origin::Exited))
+            .take_while(|r| !matches!(r.site.origin, Origin::Allowed))
+    }
+
+    /// Describe the protected regions currently being checked, starting
+    /// `skip` regions out from the innermost one
+    fn enclosing(&self, skip: usize) -> Enclosing {
+        let mut enclosing = Enclosing::NONE;
+        for (i, region) in self.active().skip(skip).enumerate() {
+            match enclosing.sites.get_mut(i) {
+                Some(site) => *site = Some(region.site),
+                None => enclosing.more += 1,
+            }
+        }
+        enclosing
+    }
+}
+
+/// Violations recorded by the allocator that haven't been reported yet;
+/// only the first is kept, since that's the one worth tracking down.
+struct PendingViolation {
+    first: Option<AllocationViolation>,
+    others: usize,
+}
+
+impl PendingViolation {
+    const NONE: PendingViolation = PendingViolation {
+        first: None,
+        others: 0,
+    };
+
+    fn record(&mut self, violation: AllocationViolation) {
+        match self.first {
+            None => self.first = Some(violation),
+            Some(_) => self.others += 1,
+        }
+    }
+
+    fn take(&mut self) -> Option<AllocationViolation> {
+        let others = self.others;
+        self.others = 0;
+        self.first
+            .take()
+            .map(|v| AllocationViolation { others, ..v })
+    }
+}
+
+/// The QADAPT allocator itself
+///
+/// To make use of the allocator, include this code block in your program
+/// binaries/tests:
+///
+/// ```rust
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     # if qadapt::is_active() {
+///     assert!(qadapt::is_active());
+///     # }
+/// }
+/// ```
+///
+/// By default QADAPT hands memory requests off to the [`System`] allocator.
+/// If your program normally runs with a different allocator, use [`QADAPT::wrap`]
+/// so that protection checks sit on top of the allocator you actually ship with:
+///
+/// ```rust
+/// use qadapt::QADAPT;
+/// use std::alloc::System;
+///
+/// // Any `GlobalAlloc` implementation works here; `System` stands in
+/// // for something like jemalloc or mimalloc.
+/// #[global_allocator]
+/// static Q: QADAPT<System> = QADAPT::wrap(System);
+///
+/// fn main() {
+///     # if qadapt::is_active() {
+///     assert!(qadapt::is_active());
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub struct QADAPT<A = System> {
+    inner: A,
+}
+
+/// The default QADAPT allocator, backed by the [`System`] allocator.
+///
+/// This constant allows for declaring the allocator without any extra setup:
+/// `static Q: QADAPT = QADAPT;`
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub const QADAPT: QADAPT = QADAPT { inner: System };
+
+impl<A> QADAPT<A> {
+    /// Build a QADAPT allocator that forwards all memory requests to `inner`.
+    ///
+    /// Because this is a `const fn`, it can be used to declare the
+    /// `#[global_allocator]` static:
+    ///
+    /// ```rust
+    /// use qadapt::QADAPT;
+    /// use std::alloc::System;
+    ///
+    /// #[global_allocator]
+    /// static Q: QADAPT<System> = QADAPT::wrap(System);
+    ///
+    /// fn main() {
+    ///     # if qadapt::is_active() {
+    ///     assert!(qadapt::is_active());
+    ///     # }
+    /// }
+    /// ```
+    pub const fn wrap(inner: A) -> Self {
+        QADAPT { inner }
+    }
+}
+
+/// Let QADAPT know that we are now entering a protected region and that
+/// panics should be triggered if allocations/drops happen while we are running.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected;
+/// use qadapt::exit_protected;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     // Force an allocation by using a Box
+///     let x = Box::new(2);
+///
+///     enter_protected();
+///     // We're now in a memory-protected region - allocations and drops
+///     // here will trigger thread panic
+///     let y = *x * 4;
+///     exit_protected();
+///
+///     // It's now safe to allocate/drop again
+///     let z = Box::new(y);
+/// }
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn enter_protected() {
+    enter_region(Region::entered(Origin::Call("enter_protected")));
+}
+
+/// Enter a protected region that lasts until the returned [`ProtectionGuard`] is dropped.
+///
+/// Unlike [`enter_protected`], the region is always exited properly,
+/// including for early returns, `?`, and panics.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::protect;
+/// use qadapt::protection_level;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn parse(input: &str) -> Result<u32, std::num::ParseIntError> {
+///     let _guard = protect();
+///     // Leaving early still exits the protected region
+///     let x: u32 = input.parse()?;
+///     Ok(x * 2)
+/// }
+///
+/// fn main() {
+///     assert!(parse("forty-two").is_err());
+///     assert_eq!(protection_level(), 0);
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn protect() -> ProtectionGuard {
+    ProtectionGuard::new(enter_region(Region::entered(Origin::Call("protect"))))
+}
+
+/// Enter a protected region that handles violations using `policy`,
+/// rather than the global [`violation_policy`]. Regions nested inside
+/// this one will use their own policy.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected_with_policy;
+/// use qadapt::exit_protected;
+/// use qadapt::ViolationPolicy;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     enter_protected_with_policy(ViolationPolicy::Log);
+///     // This allocation is written to stderr instead of triggering a panic
+///     let _b = Box::new(12);
+///     exit_protected();
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn enter_protected_with_policy(policy: ViolationPolicy) {
+    enter_region(Region::entered(Origin::Call("enter_protected_with_policy")).with_policy(policy));
+}
+
+/// Enter a protected region that handles violations using `policy`, lasting
+/// until the returned [`ProtectionGuard`] is dropped.
+/// See [`enter_protected_with_policy`] and [`protect`].
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn protect_with_policy(policy: ViolationPolicy) -> ProtectionGuard {
+    ProtectionGuard::new(enter_region(
+        Region::entered(Origin::Call("protect_with_policy")).with_policy(policy),
+    ))
+}
+
+/// Enter a protected region that is allowed to make up to `count` allocations
+/// and request up to `bytes` bytes in total; `None` leaves that part of the
+/// budget unlimited. Allocations past the budget are handled like any other
+/// violation, and freeing memory is always allowed.
+///
+/// Allocations are still checked against every protected region around
+/// this one, so a budget can't be used to allow allocations inside a
+/// `#[no_alloc]` function; use `allow_alloc!` or `#[allow_alloc]` for that.
+///
+/// This is the runtime support for the `#[alloc_budget]` macro.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected_with_budget;
+/// use qadapt::exit_protected;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     enter_protected_with_budget(Some(1), Some(16));
+///     // One allocation, well within budget
+///     let v: Vec<u8> = Vec::with_capacity(8);
+///     exit_protected();
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn enter_protected_with_budget(count: Option<usize>, bytes: Option<usize>) {
+    enter_region(
+        Region::entered(Origin::Call("enter_protected_with_budget")).with_budget(count, bytes),
+    );
+}
+
+/// Enter a protected region with an allocation budget, lasting until the
+/// returned [`ProtectionGuard`] is dropped.
+/// See [`enter_protected_with_budget`] and [`protect`].
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn protect_with_budget(count: Option<usize>, bytes: Option<usize>) -> ProtectionGuard {
+    ProtectionGuard::new(enter_region(
+        Region::entered(Origin::Call("protect_with_budget")).with_budget(count, bytes),
+    ))
+}
+
+/// Enter a protected region with a name. The name is included in violation
+/// messages, is used to track [`region_violation_count`], and is listed
+/// by [`current_regions`] while the region is active.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected_named;
+/// use qadapt::exit_protected;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     enter_protected_named("order_book::match");
+///     // Violations here are reported as happening inside `region "order_book::match"`
+///     let x = 2 + 2;
+///     exit_protected();
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn enter_protected_named(name: &'static str) {
+    enter_region(Region::entered(Origin::Call("enter_protected_named")).with_name(Some(name)));
+}
+
+/// Enter a named protected region, lasting until the returned [`ProtectionGuard`]
+/// is dropped. See [`enter_protected_named`] and [`protect`].
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+pub fn protect_named(name: &'static str) -> ProtectionGuard {
+    ProtectionGuard::new(enter_region(
+        Region::entered(Origin::Call("protect_named")).with_name(Some(name)),
+    ))
+}
+
+/// Run a closure in a protected region, and get back the first violation
+/// it caused instead of panicking. If the closure doesn't touch the allocator,
+/// its result is returned.
+///
+/// Violations inside the closure are captured regardless of [`ViolationPolicy`],
+/// including those in protected regions nested inside it, and aren't included
+/// in [`violation_count`]. In release builds, the closure's result is always returned.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::try_no_alloc;
+/// use qadapt::ViolationKind;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     assert_eq!(try_no_alloc(|| 2 + 2).unwrap(), 4);
+///
+///     let res = try_no_alloc(|| Box::new(12));
+///     # if qadapt::is_active() {
+///     let violation = res.unwrap_err();
+///     assert_eq!(violation.kind, ViolationKind::Allocation);
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[track_caller]
+// Boxing the violation would mean allocating, which is what we're trying to catch
+#[allow(clippy::result_large_err)]
+pub fn try_no_alloc<T, F: FnOnce() -> T>(f: F) -> Result<T, AllocationViolation> {
+    let capture = CaptureGuard::new();
+    let guard = ProtectionGuard::new(enter_region(
+        Region::entered(Origin::Call("try_no_alloc")).capturing(),
+    ));
+    let result = f();
+    drop(guard);
+
+    let captured = take_captured_violation();
+    drop(capture);
+    match captured {
+        Some(violation) => Err(violation),
+        None => Ok(result),
+    }
+}
+
+/// Keeps violations captured for an enclosing `try_no_alloc` call separate
+/// from the current one, and puts them back once the current call finishes
+struct CaptureGuard {
+    outer: PendingViolation,
+}
+
+impl CaptureGuard {
+    fn new() -> CaptureGuard {
+        let outer = CAPTURED_VIOLATION
+            .try_with(|p| mem::replace(&mut *p.write(), PendingViolation::NONE))
+            .unwrap_or(PendingViolation::NONE);
+        CaptureGuard { outer }
+    }
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        let outer = mem::replace(&mut self.outer, PendingViolation::NONE);
+        CAPTURED_VIOLATION
+            .try_with(|p| *p.write() = outer)
+            .unwrap_or(());
+    }
+}
+
+/// Runtime support for `assert_no_alloc!`; not public API
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_no_alloc(
+    name: Option<&'static str>,
+    slot: Option<&'static RegionSlot>,
+) -> ProtectionGuard {
+    let region = Region::entered(Origin::Macro("assert_no_alloc")).with_name(name);
+    let region = match slot {
+        Some(slot) => region.counted_in(slot),
+        None => region,
+    };
+    ProtectionGuard::new(enter_region(region))
+}
 
-// thread_id is necessary because `std::thread::current()` panics if we have not yet
-// allocated a `thread_local!{}` it depends on.
-use thread_id;
+/// Set up the region for a `#[no_alloc]` function, with the options given to the attribute
+fn no_alloc_region(
+    path: &'static str,
+    location: &'static Location<'static>,
+    name: Option<&'static str>,
+    policy: Option<ViolationPolicy>,
+    allow_alloc: bool,
+    allow_dealloc: bool,
+) -> Region {
+    let region = Region::at(
+        Origin::Function {
+            attr: "no_alloc",
+            path,
+        },
+        location,
+    );
+    let region = region.with_name(name).allowing(Allowed {
+        allocations: allow_alloc,
+        deallocations: allow_dealloc,
+    });
+    Region { policy, ..region }
+}
 
-// Re-export the proc macros to use by other code
-pub use qadapt_macro::*;
+/// Runtime support for `#[no_alloc]`; not public API
+#[doc(hidden)]
+#[track_caller]
+pub fn __no_alloc(
+    path: &'static str,
+    slot: &'static RegionSlot,
+    name: Option<&'static str>,
+    policy: Option<ViolationPolicy>,
+    allow_alloc: bool,
+    allow_dealloc: bool,
+) -> ProtectionGuard {
+    let region = no_alloc_region(
+        path,
+        Location::caller(),
+        name,
+        policy,
+        allow_alloc,
+        allow_dealloc,
+    );
+    ProtectionGuard::new(enter_region(region.counted_in(slot)))
+}
 
-use spin::RwLock;
-use std::alloc::GlobalAlloc;
-use std::alloc::Layout;
-use std::alloc::System;
-use std::thread;
+/// Runtime support for `#[no_alloc]` on `async fn` and functions returning
+/// `impl Future`; not public API
+#[doc(hidden)]
+#[track_caller]
+pub fn __no_alloc_future<F: std::future::Future>(
+    path: &'static str,
+    slot: &'static RegionSlot,
+    name: Option<&'static str>,
+    policy: Option<ViolationPolicy>,
+    allow_alloc: bool,
+    allow_dealloc: bool,
+    future: F,
+) -> future::NoAllocFuture<F> {
+    let region = no_alloc_region(
+        path,
+        Location::caller(),
+        name,
+        policy,
+        allow_alloc,
+        allow_dealloc,
+    );
+    future::NoAllocFuture::new(region.counted_in(slot), future)
+}
 
-thread_local! {
-    static PROTECTION_LEVEL: RwLock<usize> = RwLock::new(0);
+/// Runtime support for `#[alloc_budget]`; not public API
+#[doc(hidden)]
+#[track_caller]
+pub fn __alloc_budget(
+    path: &'static str,
+    slot: &'static RegionSlot,
+    count: Option<usize>,
+    bytes: Option<usize>,
+) -> ProtectionGuard {
+    let region = Region::entered(Origin::Function {
+        attr: "alloc_budget",
+        path,
+    });
+    let region = region.counted_in(slot).with_budget(count, bytes);
+    ProtectionGuard::new(enter_region(region))
 }
-static IS_ACTIVE: RwLock<bool> = RwLock::new(false);
-static INTERNAL_ALLOCATION: RwLock<usize> = RwLock::new(usize::max_value());
 
-/// The QADAPT allocator itself
+/// Marker for a protected region, created by [`protect`]. The region is
+/// exited when the guard is dropped.
 ///
-/// To make use of the allocator, include this code block in your program
-/// binaries/tests:
+/// Because protection is tracked per-thread, guards can't be sent to other threads:
+///
+/// ```rust,compile_fail
+/// let guard = qadapt::protect();
+/// std::thread::spawn(move || drop(guard));
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[must_use = "the protected region is exited as soon as the guard is dropped"]
+pub struct ProtectionGuard {
+    entered: Option<StackEntry>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ProtectionGuard {
+    fn new(entered: Option<StackEntry>) -> Self {
+        ProtectionGuard {
+            entered,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for ProtectionGuard {
+    fn drop(&mut self) {
+        counters::exit();
+        let entry = match self.entered {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        // If a violation already panicked, QADAPT has cleaned up all regions
+        // on this thread and there's nothing left to do
+        let counted = match REGIONS.try_with(|r| r.write().remove_protected(entry)) {
+            Ok(Some(counted)) => counted,
+            _ => return,
+        };
+        if counted {
+            PROTECTION_LEVEL
+                .try_with(|v| {
+                    let mut level = v.write();
+                    *level = level.saturating_sub(1);
+                })
+                .unwrap_or(());
+        }
+
+        // Don't report anything while we're unwinding out of the region
+        if !thread::panicking() {
+            report_pending_violation();
+        }
+    }
+}
+
+/// Allow allocations until the returned [`AllowGuard`] is dropped, even when
+/// inside a protected region. This is useful for deliberate allocations,
+/// like building an error message on a cold path.
+///
+/// The current protection level is set to 0, and restored once the guard is dropped.
+/// Protected regions entered while allocations are allowed are checked as usual.
+///
+/// **Example**:
 ///
 /// ```rust
+/// use qadapt::allow_allocations;
+/// use qadapt::protect;
 /// use qadapt::QADAPT;
 ///
 /// #[global_allocator]
 /// static Q: QADAPT = QADAPT;
 ///
+/// fn checked_div(x: u32, y: u32) -> Result<u32, String> {
+///     let _guard = protect();
+///     if y == 0 {
+///         let _allow = allow_allocations();
+///         return Err(format!("Can't divide {} by zero", x));
+///     }
+///     Ok(x / y)
+/// }
+///
 /// fn main() {
-///     # if qadapt::is_active() {
-///     assert!(qadapt::is_active());
-///     # }
+///     assert_eq!(checked_div(12, 4), Ok(3));
+///     assert!(checked_div(12, 0).is_err());
 /// }
 /// ```
 #[deprecated(
     since = "1.0.3",
     note = "Please use the `alloc_counter` crate instead."
 )]
-pub struct QADAPT;
+pub fn allow_allocations() -> AllowGuard {
+    counters::allow();
+    AllowGuard {
+        suspended: suspend_protection(),
+        _not_send: PhantomData,
+    }
+}
+
+/// Marker for a region where allocations are allowed, created by [`allow_allocations`].
+/// The previous protection level is restored when the guard is dropped.
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[must_use = "allocations are only allowed until the guard is dropped"]
+pub struct AllowGuard {
+    suspended: Option<(StackEntry, usize)>,
+    _not_send: PhantomData<*const ()>,
+}
 
-static SYSTEM_ALLOC: System = System;
+impl Drop for AllowGuard {
+    fn drop(&mut self) {
+        counters::exit();
+        if let Some((entry, saved)) = self.suspended {
+            let current = PROTECTION_LEVEL.try_with(|v| *v.read()).unwrap_or(0);
+            let restored = REGIONS.try_with(|r| r.write().remove_allowed(entry, saved, current));
+            if let Ok(Some(level)) = restored {
+                PROTECTION_LEVEL
+                    .try_with(|v| *v.write() = level)
+                    .unwrap_or(());
+            }
+        }
+    }
+}
 
-/// Let QADAPT know that we are now entering a protected region and that
-/// panics should be triggered if allocations/drops happen while we are running.
+/// Evaluate an expression with allocations allowed, even when inside
+/// a protected region. See [`allow_allocations`].
 ///
 /// **Example**:
 ///
 /// ```rust
-/// use qadapt::enter_protected;
-/// use qadapt::exit_protected;
+/// use qadapt::allow_alloc;
+/// use qadapt::assert_no_alloc;
 /// use qadapt::QADAPT;
 ///
 /// #[global_allocator]
 /// static Q: QADAPT = QADAPT;
 ///
 /// fn main() {
-///     // Force an allocation by using a Box
-///     let x = Box::new(2);
+///     let b = assert_no_alloc!({
+///         let x = 2 + 2;
+///         allow_alloc!(Box::new(x))
+///     });
+///     assert_eq!(*b, 4);
+/// }
+/// ```
 ///
-///     enter_protected();
-///     // We're now in a memory-protected region - allocations and drops
-///     // here will trigger thread panic
-///     let y = *x * 4;
-///     exit_protected();
+/// Inside of functions marked `#[no_alloc]`, statements can be marked `#[allow_alloc]` instead:
 ///
-///     // It's now safe to allocate/drop again
-///     let z = Box::new(y);
+/// ```rust
+/// use qadapt::no_alloc;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// #[no_alloc]
+/// fn checked_div(x: u32, y: u32) -> Result<u32, String> {
+///     if y == 0 {
+///         #[allow_alloc]
+///         return Err(format!("Can't divide {} by zero", x));
+///     }
+///     Ok(x / y)
 /// }
+///
+/// fn main() {
+///     assert!(checked_div(12, 0).is_err());
+/// }
+/// ```
+#[macro_export]
 #[deprecated(
     since = "1.0.3",
     note = "Please use the `alloc_counter` crate instead."
 )]
-pub fn enter_protected() {
-    #[cfg(debug_assertions)]
+macro_rules! allow_alloc {
+    ($e:expr) => {{
+        let __qadapt_allow = ::qadapt::allow_allocations();
+        let e = { $e };
+        ::std::mem::drop(__qadapt_allow);
+        e
+    }};
+}
+
+/// Set the protection level to 0 until allocations are no longer allowed,
+/// returning the placeholder's entry and the level to restore afterward
+fn suspend_protection() -> Option<(StackEntry, usize)> {
+    #[cfg(any(debug_assertions, feature = "release-checks"))]
     {
         if thread::panicking() || !is_active() {
-            return;
+            return None;
+        }
+
+        let level = PROTECTION_LEVEL
+            .try_with(|v| {
+                let mut level = v.write();
+                let prev = *level;
+                *level = 0;
+                prev
+            })
+            .ok()?;
+        // Keep a placeholder on the region stack so regions entered while
+        // allocations are allowed don't overwrite the ones we're suspending
+        let entry = REGIONS.try_with(|r| r.write().push_allowed(level)).ok()?;
+        Some((entry, level))
+    }
+
+    #[cfg(not(any(debug_assertions, feature = "release-checks")))]
+    None
+}
+
+/// Enter a protected region, returning its entry if QADAPT is actually tracking it
+#[cfg_attr(
+    not(any(debug_assertions, feature = "release-checks")),
+    allow(unused_variables)
+)]
+fn enter_region(region: Region) -> Option<StackEntry> {
+    counters::enter(
+        region
+            .counter
+            .unwrap_or_else(|| counters::lookup(region.site.name())),
+    );
+
+    #[cfg(any(debug_assertions, feature = "release-checks"))]
+    {
+        if thread::panicking() || !is_active() {
+            return None;
         }
 
         PROTECTION_LEVEL
             .try_with(|v| {
                 *v.write() += 1;
             })
-            .unwrap_or_else(|_e| ());
+            .unwrap_or(());
+        REGIONS
+            .try_with(|r| {
+                let mut regions = r.write();
+                if regions.depth == 0 {
+                    // Anything left over from a region we unwound out of
+                    // doesn't belong to this one
+                    take_pending_violation();
+                    take_captured_violation();
+                }
+                regions.push(region)
+            })
+            .ok()
     }
+
+    #[cfg(not(any(debug_assertions, feature = "release-checks")))]
+    None
 }
 
 /// Let QADAPT know that we are exiting a protected region. Will panic
 /// if we attempt to [`exit_protected`] more times than we [`enter_protected`].
 ///
+/// Unless [`set_immediate_panic`] has been enabled, this is also where QADAPT reports
+/// any allocations that happened while protected: the allocations themselves succeed,
+/// and the panic is triggered once the region is exited.
+///
 /// **Example**:
 ///
 /// ```rust
@@ -206,7 +1649,9 @@ pub fn enter_protected() {
     note = "Please use the `alloc_counter` crate instead."
 )]
 pub fn exit_protected() {
-    #[cfg(debug_assertions)]
+    counters::exit();
+
+    #[cfg(any(debug_assertions, feature = "release-checks"))]
     {
         if thread::panicking() || !is_active() {
             return;
@@ -216,14 +1661,70 @@ pub fn exit_protected() {
             .try_with(|v| {
                 let val = { *v.read() };
                 match val {
-                    v if v == 0 => panic!("Attempt to exit protected too many times"),
+                    0 => panic!("Attempt to exit protected too many times"),
                     _ => {
                         *v.write() -= 1;
                     }
                 }
             })
-            .unwrap_or_else(|_e| ());
+            .unwrap_or(());
+        REGIONS.try_with(|r| r.write().pop()).unwrap_or(());
+
+        report_pending_violation();
+    }
+}
+
+/// Choose whether QADAPT panics from inside the allocator at the moment an
+/// allocation happens in a protected region.
+///
+/// By default, QADAPT lets the offending allocation succeed and triggers
+/// the panic when the protected region is exited (via [`exit_protected`], or
+/// the end of a `#[no_alloc]` function or `assert_no_alloc!` block).
+/// `GlobalAlloc` implementations are not allowed to unwind, so panicking from
+/// inside the allocator is undefined behavior; it can however be useful
+/// for getting a stack trace that points at the exact allocation.
+///
+/// Optimized code relies on the allocator not unwinding, so this setting is ignored
+/// in release builds using the `release-checks` feature; QADAPT writes a note
+/// to stderr when that happens.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected;
+/// use qadapt::set_immediate_panic;
+/// use qadapt::QADAPT;
+/// use std::panic::catch_unwind;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     set_immediate_panic(true);
+///
+///     let res = catch_unwind(|| {
+///         enter_protected();
+///         // With immediate panics, we never make it past this allocation
+///         let _b = Box::new(12);
+///         unreachable!()
+///     });
+///     # if qadapt::is_active() {
+///     assert!(res.is_err());
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn set_immediate_panic(immediate: bool) {
+    if immediate && !cfg!(debug_assertions) && cfg!(feature = "release-checks") {
+        policy::log_line(format_args!(
+            "immediate panics aren't supported in release builds; \
+             violations will be reported when the protected region exits"
+        ));
     }
+    IMMEDIATE_PANIC.store(immediate, Ordering::Relaxed);
 }
 
 /// Get the result of an expression, guaranteeing that no memory accesses occur
@@ -243,35 +1744,45 @@ pub fn exit_protected() {
 /// }
 /// ```
 ///
-/// **Warning**: Unexpected behavior will occur when using the `return` keyword.
-/// Because QADAPT doesn't have an opportunity to clean up, there may be a panic
-/// in code that was not intended to be allocation-free. The compiler will warn you
-/// that there is an unreachable statement if this happens.
+/// The protected region is exited even if the expression returns early:
 ///
 /// ```rust
 /// use qadapt::assert_no_alloc;
+/// use qadapt::protection_level;
 /// use qadapt::QADAPT;
-/// use std::panic::catch_unwind;
 ///
 /// #[global_allocator]
 /// static Q: QADAPT = QADAPT;
 ///
+/// #[allow(unreachable_code)]
 /// fn early_return() -> usize {
 ///     assert_no_alloc!(return 8);
 /// }
 ///
 /// fn main() {
 ///     let x = early_return();
-///     
-///     // Even though only the `early_return` function contains
-///     // QADAPT allocation guards, this triggers a panic:
-///     // `Box::new` forces an allocation, and QADAPT still thinks
-///     // we're in a protected region because of the return in  `early_return()`
-///     # if qadapt::is_active() {
-///     let res = catch_unwind(|| Box::new(x));
-///     assert!(res.is_err());
-///     # }
+///     assert_eq!(protection_level(), 0);
+///
+///     // It's safe to allocate again
+///     let _b = Box::new(x);
+/// }
+/// ```
+///
+/// The region can be given a name, which is used in violation messages
+/// and by [`current_regions`]:
+///
+/// ```rust
+/// use qadapt::assert_no_alloc;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     let x = assert_no_alloc!("math", 2 + 2);
+///     assert_eq!(x, 4);
 /// }
+/// ```
 #[macro_export]
 #[deprecated(
     since = "1.0.3",
@@ -279,14 +1790,37 @@ pub fn exit_protected() {
 )]
 macro_rules! assert_no_alloc {
     ($e:expr) => {{
-        ::qadapt::enter_protected();
+        let __qadapt_guard =
+            ::qadapt::__assert_no_alloc(::std::option::Option::None, ::std::option::Option::None);
+        let e = { $e };
+        ::std::mem::drop(__qadapt_guard);
+        e
+    }};
+    // Names that are known up front can have their counters looked up just once
+    ($name:literal, $e:expr) => {{
+        static SLOT: ::qadapt::__RegionSlot = ::qadapt::__RegionSlot::new();
+        let __qadapt_guard = ::qadapt::__assert_no_alloc(
+            ::std::option::Option::Some($name),
+            ::std::option::Option::Some(&SLOT),
+        );
+        let e = { $e };
+        ::std::mem::drop(__qadapt_guard);
+        e
+    }};
+    ($name:expr, $e:expr) => {{
+        let __qadapt_guard = ::qadapt::__assert_no_alloc(
+            ::std::option::Option::Some($name),
+            ::std::option::Option::None,
+        );
         let e = { $e };
-        ::qadapt::exit_protected();
+        ::std::mem::drop(__qadapt_guard);
         e
     }};
 }
 
 /// Get the current "protection level" in QADAPT: calls to `enter_protected() - exit_protected()`.
+/// While allocations are allowed using [`allow_allocations`] or `allow_alloc!`,
+/// only regions entered since then are counted.
 ///
 /// **Note**: For release builds, `protection_level()` will always return 0.
 ///
@@ -324,12 +1858,61 @@ pub fn protection_level() -> usize {
     PROTECTION_LEVEL.try_with(|v| *v.read()).unwrap_or(0)
 }
 
+/// Get the names of the protected regions the current thread is in,
+/// from outermost to innermost. Regions entered by `#[no_alloc]` functions
+/// are named after the function path; regions without a name aren't listed.
+///
+/// Like [`protection_level`], regions suspended by [`allow_allocations`] aren't included.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::current_regions;
+/// use qadapt::no_alloc;
+/// use qadapt::protect_named;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// #[no_alloc]
+/// fn regions() -> [Option<&'static str>; 3] {
+///     let mut names = current_regions();
+///     [names.next(), names.next(), names.next()]
+/// }
+///
+/// fn main() {
+///     let _guard = protect_named("outer");
+///     # if qadapt::is_active() {
+///     assert_eq!(regions(), [Some("outer"), Some("rust_out::regions"), None]);
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn current_regions() -> impl Iterator<Item = &'static str> {
+    let mut names = [None; MAX_REGIONS];
+    REGIONS
+        .try_with(|r| {
+            let regions = r.read();
+            let active = regions.active().filter_map(|r| r.site.name());
+            for (slot, name) in names.iter_mut().rev().zip(active) {
+                *slot = Some(name);
+            }
+        })
+        .unwrap_or(());
+    IntoIterator::into_iter(names).flatten()
+}
+
 /// Determine whether QADAPT will trigger thread panics if an allocation happens
 /// during protected code. This should be used for making sure that QADAPT is
 /// properly set up and initialized.
 ///
 /// Note that this will return `false` in release builds even if QADAPT is set
-/// as the `#[global_allocator]`.
+/// as the `#[global_allocator]`, unless the `release-checks` feature is enabled.
+/// It also returns `false` when the `QADAPT_DISABLE` environment variable turns checks off.
 ///
 /// **Example**:
 ///
@@ -351,98 +1934,315 @@ pub fn protection_level() -> usize {
     note = "Please use the `alloc_counter` crate instead."
 )]
 pub fn is_active() -> bool {
-    if cfg!(debug_assertions) {
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
         // Because there are heap allocations that happen before `fn main()`,
         // we don't need to force an extra allocation here to guarantee that
         // IS_ACTIVE is set
-        *IS_ACTIVE.read()
+        IS_ACTIVE.load(Ordering::Relaxed) && !config::disabled()
     } else {
         false
     }
 }
 
+/// Let allocations made by QADAPT itself on the current thread (for example,
+/// while setting up thread-locals) pass straight through to the allocator
 fn claim_internal_alloc() {
-    loop {
-        match INTERNAL_ALLOCATION.write() {
-            ref mut lock if **lock == usize::max_value() => {
-                **lock = thread_id::get();
-                break;
-            }
-            _ => (),
-        }
-    }
+    INTERNAL_ALLOCATION
+        .try_with(|claimed| claimed.set(true))
+        .unwrap_or(());
 }
 
 fn release_internal_alloc() {
-    match INTERNAL_ALLOCATION.write() {
-        ref mut lock if **lock == thread_id::get() => **lock = usize::max_value(),
-        _ => panic!("Internal allocation tracking error"),
+    let claimed = INTERNAL_ALLOCATION
+        .try_with(|claimed| claimed.replace(false))
+        .unwrap_or(true);
+    if !claimed {
+        panic!("Internal allocation tracking error");
     }
 }
 
 fn alloc_immediate() -> bool {
-    thread::panicking() || *INTERNAL_ALLOCATION.read() == thread_id::get()
+    thread::panicking()
+        || config::disabled()
+        || INTERNAL_ALLOCATION.try_with(Cell::get).unwrap_or(true)
 }
 
-unsafe impl GlobalAlloc for QADAPT {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if !*IS_ACTIVE.read() {
-            *IS_ACTIVE.write() = true;
+fn mark_active() {
+    // The first allocation happens before `fn main()`, so there's no race
+    // for the configuration to worry about
+    if !IS_ACTIVE.load(Ordering::Relaxed) && !IS_ACTIVE.swap(true, Ordering::Relaxed) {
+        config::load();
+    }
+}
+
+/// Record an allocator interaction in the thread statistics and region counters,
+/// and get the protection level it happened at
+fn track_allocation(update: impl FnOnce(&mut AllocStats)) -> usize {
+    let mut delta = AllocStats::ZERO;
+    update(&mut delta);
+    counters::record(&delta);
+
+    // Because accessing PROTECTION_LEVEL has the potential to trigger an allocation,
+    // we need to claim any allocations on our thread as internal.
+    claim_internal_alloc();
+    stats::track(|s| s.add(&delta));
+    let protection_level = PROTECTION_LEVEL.try_with(|v| *v.read()).unwrap_or(0);
+    release_internal_alloc();
+
+    protection_level
+}
+
+fn reset_protection_level() {
+    // Tripped a bad allocation, but make sure further memory access during unwind
+    // doesn't have issues
+    PROTECTION_LEVEL.with(|v| *v.write() = 0);
+    REGIONS.with(|r| r.write().reset());
+    counters::reset();
+}
+
+/// Panic with any violations recorded since entering the protected region
+fn report_pending_violation() {
+    if let Some(violation) = take_pending_violation() {
+        reset_protection_level();
+        panic_with_violation(violation);
+    }
+}
+
+/// Panic with the violation as the payload. The default panic hook can't
+/// display custom payloads, so the details are printed first.
+fn panic_with_violation(violation: AllocationViolation) -> ! {
+    let violation = AllocationViolation {
+        resolve_frames: true,
+        ..violation
+    };
+    policy::report_line(format_args!("{}", violation));
+    panic::panic_any(violation)
+}
+
+fn take_pending_violation() -> Option<AllocationViolation> {
+    PENDING_VIOLATION
+        .try_with(|p| p.write().take())
+        .unwrap_or(None)
+}
+
+/// Check an allocator interaction against the rules of every region it
+/// happened in, and report it if any of them consider it a violation.
+///
+/// Every region's budget is charged. When several regions forbid the same
+/// interaction it's reported once, for the region with the strictest policy
+/// (the innermost one if there's a tie), so a nested region can tighten
+/// the rules of the regions around it but never relax them.
+fn check_violation(violation: AllocationViolation) {
+    claim_internal_alloc();
+    let checked = REGIONS
+        .try_with(|r| {
+            let mut regions = r.write();
+            let violation = AllocationViolation {
+                thread_id: thread_id::get(),
+                ..violation
+            };
+            let capture = regions.active().any(|r| r.capture);
+
+            let mut checked: Option<(usize, AllocationViolation, ViolationPolicy)> = None;
+            if regions.depth == 0 {
+                checked = Some((0, violation, violation_policy()));
+            }
+            for (i, region) in regions.active_mut().enumerate() {
+                if let Some((v, policy)) = region.check(violation) {
+                    let policy = policy.unwrap_or_else(violation_policy);
+                    let stricter = match checked {
+                        Some((_, _, strictest)) => policy.severity() > strictest.severity(),
+                        None => true,
+                    };
+                    if stricter {
+                        checked = Some((i, v, policy));
+                    }
+                }
+            }
+
+            checked.map(|(i, v, policy)| {
+                let enclosing = regions.enclosing(i);
+                let v = AllocationViolation {
+                    region: enclosing.name(),
+                    enclosing,
+                    ..v
+                };
+                (v, policy, capture)
+            })
+        })
+        .unwrap_or_else(|_| Some((violation, violation_policy(), false)));
+    release_internal_alloc();
+
+    match checked {
+        Some((violation, _, true)) => capture_violation(violation.with_frames()),
+        Some((violation, policy, false)) => report_violation(violation, policy),
+        None => (),
+    }
+}
+
+/// Handle a violation according to the region's [`ViolationPolicy`]
+fn report_violation(violation: AllocationViolation, policy: ViolationPolicy) {
+    if policy == ViolationPolicy::Ignore {
+        return;
+    }
+    policy::count_violation(violation.region);
+
+    let violation = violation.with_frames();
+    if !policy::call_violation_hook(&violation) {
+        return;
+    }
+
+    if config::trap_violations() && policy != ViolationPolicy::Trap {
+        policy::log_line(format_args!("{}", violation));
+        qadapt_violation_breakpoint();
+    }
+
+    match policy {
+        ViolationPolicy::Panic
+            if cfg!(debug_assertions) && IMMEDIATE_PANIC.load(Ordering::Relaxed) =>
+        {
+            reset_protection_level();
+            panic_with_violation(violation);
+        }
+        ViolationPolicy::Panic => record_violation(violation),
+        ViolationPolicy::Abort => {
+            policy::log_line(format_args!(
+                "{}{}",
+                violation,
+                trace::Unresolved(&violation.frames)
+            ));
+            process::abort();
+        }
+        ViolationPolicy::Log => policy::log_line(format_args!(
+            "{}{}",
+            violation,
+            trace::Unresolved(&violation.frames)
+        )),
+        ViolationPolicy::Trap => {
+            policy::log_line(format_args!(
+                "{}{}",
+                violation,
+                trace::Unresolved(&violation.frames)
+            ));
+            qadapt_violation_breakpoint();
         }
+        ViolationPolicy::Count | ViolationPolicy::Ignore => (),
+    }
+}
+
+/// Hold on to a violation until the protected region is exited
+fn record_violation(violation: AllocationViolation) {
+    claim_internal_alloc();
+    PENDING_VIOLATION
+        .try_with(|p| p.write().record(violation))
+        .unwrap_or(());
+    release_internal_alloc();
+}
+
+/// Hold on to a violation for the `try_no_alloc` call it happened in
+fn capture_violation(violation: AllocationViolation) {
+    claim_internal_alloc();
+    CAPTURED_VIOLATION
+        .try_with(|p| p.write().record(violation))
+        .unwrap_or(());
+    release_internal_alloc();
+}
+
+fn take_captured_violation() -> Option<AllocationViolation> {
+    CAPTURED_VIOLATION
+        .try_with(|p| p.write().take())
+        .unwrap_or(None)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for QADAPT<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        mark_active();
 
         // If we're attempting to allocate our PROTECTION_LEVEL thread local,
         // just allow it through
         if alloc_immediate() {
-            return SYSTEM_ALLOC.alloc(layout);
+            return self.inner.alloc(layout);
         }
 
-        // Because accessing PROTECTION_LEVEL has the potential to trigger an allocation,
-        // we need to acquire the INTERNAL_ALLOCATION lock for our thread.
-        claim_internal_alloc();
-        let protection_level: Result<usize, ()> =
-            PROTECTION_LEVEL.try_with(|v| *v.read()).or(Ok(0));
-        release_internal_alloc();
-
+        let protection_level = track_allocation(|s| {
+            s.allocations += 1;
+            s.bytes_allocated += layout.size();
+        });
         match protection_level {
-            Ok(v) if v == 0 => SYSTEM_ALLOC.alloc(layout),
-            Ok(v) => {
-                // Tripped a bad allocation, but make sure further memory access during unwind
-                // doesn't have issues
-                PROTECTION_LEVEL.with(|v| *v.write() = 0);
-                panic!(
-                    "Unexpected allocation for size {}, protection level: {}",
-                    layout.size(),
-                    v
-                )
-            }
-            Err(_) => unreachable!(),
+            0 => (),
+            v => check_violation(AllocationViolation::new(
+                ViolationKind::Allocation,
+                &layout,
+                v,
+            )),
         }
+        self.inner.alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if alloc_immediate() {
-            return SYSTEM_ALLOC.dealloc(ptr, layout);
+            return self.inner.dealloc(ptr, layout);
         }
 
-        claim_internal_alloc();
-        let protection_level: Result<usize, ()> =
-            PROTECTION_LEVEL.try_with(|v| *v.read()).or(Ok(0));
-        release_internal_alloc();
+        let protection_level = track_allocation(|s| {
+            s.deallocations += 1;
+            s.bytes_deallocated += layout.size();
+        });
 
         // Free before checking panic to make sure we avoid leaks
-        SYSTEM_ALLOC.dealloc(ptr, layout);
+        self.inner.dealloc(ptr, layout);
         match protection_level {
-            Ok(v) if v > 0 => {
-                // Tripped a bad drop, but make sure further memory access during unwind
-                // doesn't have issues
-                PROTECTION_LEVEL.with(|v| *v.write() = 0);
-                panic!(
-                    "Unexpected deallocation for size {}, protection level: {}",
-                    layout.size(),
-                    v
-                )
-            }
-            _ => (),
+            0 => (),
+            v => check_violation(AllocationViolation::new(
+                ViolationKind::Deallocation,
+                &layout,
+                v,
+            )),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        mark_active();
+
+        if alloc_immediate() {
+            return self.inner.alloc_zeroed(layout);
+        }
+
+        let protection_level = track_allocation(|s| {
+            s.zeroed_allocations += 1;
+            s.bytes_allocated += layout.size();
+        });
+        match protection_level {
+            0 => (),
+            v => check_violation(AllocationViolation::new(
+                ViolationKind::ZeroedAllocation,
+                &layout,
+                v,
+            )),
+        }
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if alloc_immediate() {
+            return self.inner.realloc(ptr, layout, new_size);
+        }
+
+        // When panicking immediately, the original memory is untouched, so the caller
+        // is still responsible for (and able to) free it during unwind
+        let protection_level = track_allocation(|s| {
+            s.reallocations += 1;
+            s.bytes_allocated += new_size;
+            s.bytes_deallocated += layout.size();
+        });
+        match protection_level {
+            0 => (),
+            v => check_violation(AllocationViolation::new(
+                ViolationKind::Reallocation { new_size },
+                &layout,
+                v,
+            )),
         }
+        self.inner.realloc(ptr, layout, new_size)
     }
 }
@@ -0,0 +1,379 @@
+//! Control over how QADAPT reacts to allocations inside protected regions
+
+use crate::AllocationViolation;
+use spin::RwLock;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+static VIOLATION_POLICY: RwLock<ViolationPolicy> = RwLock::new(ViolationPolicy::Panic);
+static VIOLATION_COUNT: AtomicUsize = AtomicUsize::new(0);
+static VIOLATION_HOOK: RwLock<Option<ViolationHook>> = RwLock::new(None);
+static REPORT_FILE: RwLock<Option<File>> = RwLock::new(None);
+static REGION_VIOLATIONS: RwLock<[Option<(&'static str, usize)>; MAX_NAMED_REGIONS]> =
+    RwLock::new([None; MAX_NAMED_REGIONS]);
+
+/// Maximum number of region names that violations are counted for
+const MAX_NAMED_REGIONS: usize = 64;
+
+/// What QADAPT should do when a protected region interacts with the allocator.
+///
+/// The policy can be set for the whole program using [`set_violation_policy`],
+/// or for a single region using [`enter_protected_with_policy`](crate::enter_protected_with_policy).
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViolationPolicy {
+    /// Trigger a thread panic. By default, the panic happens once the protected
+    /// region is exited; see [`set_immediate_panic`](crate::set_immediate_panic).
+    Panic,
+    /// Print the violation to stderr and abort the process
+    Abort,
+    /// Print the violation to stderr and keep running. When set through
+    /// `QADAPT_MODE=log`, the process exits with a failure status at the end
+    /// if there were any violations.
+    Log,
+    /// Print the violation to stderr and stop in the debugger at the offending
+    /// allocator call. See [`qadapt_violation_breakpoint`] for details;
+    /// if the debugger continues, the program keeps running.
+    Trap,
+    /// Keep running, but include the violation in [`violation_count`]
+    Count,
+    /// Keep running, and don't track the violation at all
+    Ignore,
+}
+
+impl ViolationPolicy {
+    /// How strictly the policy treats a violation, from `Ignore` upwards
+    pub(crate) fn severity(self) -> u8 {
+        match self {
+            ViolationPolicy::Ignore => 0,
+            ViolationPolicy::Count => 1,
+            ViolationPolicy::Log => 2,
+            ViolationPolicy::Trap => 3,
+            ViolationPolicy::Panic => 4,
+            ViolationPolicy::Abort => 5,
+        }
+    }
+}
+
+/// Set the policy used for violations in protected regions that
+/// don't specify their own.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected;
+/// use qadapt::exit_protected;
+/// use qadapt::set_violation_policy;
+/// use qadapt::violation_count;
+/// use qadapt::ViolationPolicy;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     // Write violations to stderr, and check how many there were at the end
+///     set_violation_policy(ViolationPolicy::Log);
+///
+///     enter_protected();
+///     let _b = Box::new(12);
+///     exit_protected();
+///
+///     # if qadapt::is_active() {
+///     assert_eq!(violation_count(), 1);
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn set_violation_policy(policy: ViolationPolicy) {
+    *VIOLATION_POLICY.write() = policy;
+}
+
+/// Get the policy used for violations in protected regions that
+/// don't specify their own. Defaults to [`ViolationPolicy::Panic`].
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn violation_policy() -> ViolationPolicy {
+    *VIOLATION_POLICY.read()
+}
+
+/// Get the number of violations QADAPT has seen across all threads
+/// since the program started. Violations handled with [`ViolationPolicy::Ignore`]
+/// are not counted.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::assert_no_alloc;
+/// use qadapt::set_violation_policy;
+/// use qadapt::violation_count;
+/// use qadapt::ViolationPolicy;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     set_violation_policy(ViolationPolicy::Count);
+///
+///     let b = Box::new(12);
+///     assert_no_alloc!(drop(b));
+///
+///     # if qadapt::is_active() {
+///     assert_eq!(violation_count(), 1);
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn violation_count() -> usize {
+    VIOLATION_COUNT.load(Ordering::Relaxed)
+}
+
+/// Get the number of violations QADAPT has seen in protected regions named `name`,
+/// across all threads. Only the first 64 region names with violations are tracked.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::protect_named;
+/// use qadapt::region_violation_count;
+/// use qadapt::set_violation_policy;
+/// use qadapt::ViolationPolicy;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     set_violation_policy(ViolationPolicy::Count);
+///
+///     let guard = protect_named("latency_critical");
+///     let _b = Box::new(12);
+///     drop(guard);
+///
+///     # if qadapt::is_active() {
+///     assert_eq!(region_violation_count("latency_critical"), 1);
+///     # }
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn region_violation_count(name: &str) -> usize {
+    REGION_VIOLATIONS
+        .read()
+        .iter()
+        .flatten()
+        .find(|(n, _)| *n == name)
+        .map_or(0, |(_, count)| *count)
+}
+
+/// Function called for each violation, before it's handled according to the [`ViolationPolicy`]
+type ViolationHook = fn(&AllocationViolation) -> bool;
+
+/// Register a function to be called for every violation, replacing any previous hook.
+///
+/// The hook is called from inside the allocator at the moment of the violation,
+/// before the [`ViolationPolicy`] is applied; return `true` to continue with the usual
+/// handling, or `false` to skip it. Violations handled with [`ViolationPolicy::Ignore`]
+/// or captured by [`try_no_alloc`](crate::try_no_alloc) don't reach the hook.
+///
+/// Allocations made by the hook itself are allowed. The hook must not panic.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::enter_protected;
+/// use qadapt::exit_protected;
+/// use qadapt::set_violation_hook;
+/// use qadapt::AllocationViolation;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn log_violation(violation: &AllocationViolation) -> bool {
+///     eprintln!("{} bytes allocated on thread {}", violation.size, violation.thread_id);
+///     // Don't panic
+///     false
+/// }
+///
+/// fn main() {
+///     set_violation_hook(log_violation);
+///
+///     enter_protected();
+///     let _b = Box::new(12);
+///     exit_protected();
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn set_violation_hook(hook: fn(&AllocationViolation) -> bool) {
+    *VIOLATION_HOOK.write() = Some(hook);
+}
+
+/// Unregister the violation hook, returning it if one was set.
+/// See [`set_violation_hook`].
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn take_violation_hook() -> Option<fn(&AllocationViolation) -> bool> {
+    VIOLATION_HOOK.write().take()
+}
+
+/// Run the violation hook, if there is one, and get back whether
+/// the violation should still be handled by the policy
+pub(crate) fn call_violation_hook(violation: &AllocationViolation) -> bool {
+    let hook = *VIOLATION_HOOK.read();
+    match hook {
+        Some(hook) => {
+            // Let allocations made by the hook through
+            crate::claim_internal_alloc();
+            let proceed = hook(violation);
+            crate::release_internal_alloc();
+            proceed
+        }
+        None => true,
+    }
+}
+
+/// Called by QADAPT for violations handled with [`ViolationPolicy::Trap`],
+/// or for every reported violation when the `QADAPT_TRAP=1` environment variable is set.
+///
+/// On Unix, this raises `SIGTRAP`, so debuggers stop at the moment of the offending
+/// allocation, before any unwinding happens; move `up` the stack to reach the code that
+/// allocated. On other platforms, set a breakpoint on this function
+/// (`break qadapt_violation_breakpoint` in `gdb`, `b qadapt_violation_breakpoint` in `lldb`)
+/// to get the same effect.
+///
+/// Without a debugger attached, `SIGTRAP` ends the process.
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn qadapt_violation_breakpoint() {
+    #[cfg(unix)]
+    unsafe {
+        libc::raise(libc::SIGTRAP);
+    }
+
+    // Keep the function from being optimized out on other platforms
+    #[cfg(not(unix))]
+    std::hint::black_box(());
+}
+
+pub(crate) fn count_violation(region: Option<&'static str>) {
+    VIOLATION_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    if let Some(name) = region {
+        let mut regions = REGION_VIOLATIONS.write();
+        if let Some(slot) = regions.iter_mut().find(|slot| match slot {
+            Some((n, _)) => *n == name,
+            None => true,
+        }) {
+            let count = slot.map_or(0, |(_, count)| count);
+            *slot = Some((name, count + 1));
+        }
+    }
+}
+
+/// Make the process exit with a failure status if any violations were counted by
+/// the time it ends, so a whole run can log every violation and still fail
+pub(crate) fn fail_at_exit() {
+    // Safety: `exit_if_violations` doesn't unwind, and only calls `_exit`
+    unsafe {
+        libc::atexit(exit_if_violations);
+    }
+}
+
+extern "C" fn exit_if_violations() {
+    let count = violation_count();
+    if count > 0 {
+        log_line(format_args!(
+            "{} violations were logged, exiting with a failure status",
+            count
+        ));
+        // Safety: the rest of the process is already shutting down
+        unsafe { libc::_exit(1) }
+    }
+}
+
+/// Formatting target backed by a fixed-size buffer, so messages can be
+/// built from inside the allocator. Anything that doesn't fit is dropped.
+struct StackWriter {
+    buf: [u8; 1024],
+    len: usize,
+}
+
+impl fmt::Write for StackWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = self.buf.len() - self.len;
+        let len = s.len().min(remaining);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Send violation reports to a file instead of stderr
+pub(crate) fn set_report_file(file: File) {
+    *REPORT_FILE.write() = Some(file);
+}
+
+fn write_report(buf: &[u8]) {
+    match REPORT_FILE.read().as_ref() {
+        Some(mut file) => {
+            let _ = file.write_all(buf);
+        }
+        None => {
+            let _ = io::stderr().write_all(buf);
+        }
+    }
+}
+
+/// Write a line to the report output without allocating
+pub(crate) fn log_line(line: fmt::Arguments) {
+    let mut w = StackWriter {
+        buf: [0; 1024],
+        len: 0,
+    };
+    let _ = fmt::Write::write_fmt(&mut w, format_args!("qadapt: {}", line));
+
+    // Make sure the line terminator survives truncation
+    let len = w.len.min(w.buf.len() - 1);
+    w.buf[len] = b'\n';
+    write_report(&w.buf[..=len]);
+}
+
+/// Write a line to the report output. Unlike [`log_line`], the line isn't truncated,
+/// and it goes through `eprintln!` when there is no report file, so test harnesses
+/// can capture it.
+pub(crate) fn report_line(line: fmt::Arguments) {
+    match REPORT_FILE.read().as_ref() {
+        Some(mut file) => {
+            let _ = writeln!(file, "qadapt: {}", line);
+        }
+        None => eprintln!("qadapt: {}", line),
+    }
+}
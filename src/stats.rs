@@ -0,0 +1,120 @@
+//! Tracking how many allocations happen on each thread
+
+use spin::RwLock;
+
+thread_local! {
+    static ALLOC_STATS: RwLock<AllocStats> = const { RwLock::new(AllocStats::ZERO) };
+}
+
+/// Summary of the allocator interactions on a single thread
+///
+/// `realloc` calls are only counted as reallocations; their size is tracked
+/// as `bytes_deallocated` for the old memory and `bytes_allocated` for the new.
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Number of calls to `alloc`
+    pub allocations: usize,
+    /// Number of calls to `alloc_zeroed`
+    pub zeroed_allocations: usize,
+    /// Number of calls to `realloc`
+    pub reallocations: usize,
+    /// Number of calls to `dealloc`
+    pub deallocations: usize,
+    /// Total bytes requested from the allocator
+    pub bytes_allocated: usize,
+    /// Total bytes returned to the allocator
+    pub bytes_deallocated: usize,
+}
+
+impl AllocStats {
+    pub(crate) const ZERO: AllocStats = AllocStats {
+        allocations: 0,
+        zeroed_allocations: 0,
+        reallocations: 0,
+        deallocations: 0,
+        bytes_allocated: 0,
+        bytes_deallocated: 0,
+    };
+
+    pub(crate) fn add(&mut self, other: &AllocStats) {
+        // This runs inside the allocator, so overflow can't be allowed to panic
+        self.allocations = self.allocations.wrapping_add(other.allocations);
+        self.zeroed_allocations = self
+            .zeroed_allocations
+            .wrapping_add(other.zeroed_allocations);
+        self.reallocations = self.reallocations.wrapping_add(other.reallocations);
+        self.deallocations = self.deallocations.wrapping_add(other.deallocations);
+        self.bytes_allocated = self.bytes_allocated.wrapping_add(other.bytes_allocated);
+        self.bytes_deallocated = self.bytes_deallocated.wrapping_add(other.bytes_deallocated);
+    }
+
+    fn since(&self, start: &AllocStats) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations.wrapping_sub(start.allocations),
+            zeroed_allocations: self
+                .zeroed_allocations
+                .wrapping_sub(start.zeroed_allocations),
+            reallocations: self.reallocations.wrapping_sub(start.reallocations),
+            deallocations: self.deallocations.wrapping_sub(start.deallocations),
+            bytes_allocated: self.bytes_allocated.wrapping_sub(start.bytes_allocated),
+            bytes_deallocated: self.bytes_deallocated.wrapping_sub(start.bytes_deallocated),
+        }
+    }
+}
+
+/// Run a closure on the current thread, and count the allocations it makes.
+///
+/// Only allocations made by the current thread are counted. Unlike the
+/// protection functions, allocation counting is available in release builds
+/// as long as QADAPT is the global allocator.
+///
+/// **Example**:
+///
+/// ```rust
+/// use qadapt::count_allocs;
+/// use qadapt::QADAPT;
+///
+/// #[global_allocator]
+/// static Q: QADAPT = QADAPT;
+///
+/// fn main() {
+///     let stats = count_allocs(|| {
+///         let mut v = Vec::with_capacity(4);
+///         v.push(1u32);
+///         std::hint::black_box(v);
+///     });
+///
+///     assert_eq!(stats.allocations, 1);
+///     assert_eq!(stats.deallocations, 1);
+///     assert_eq!(stats.bytes_allocated, 16);
+/// }
+/// ```
+#[deprecated(
+    since = "1.0.3",
+    note = "Please use the `alloc_counter` crate instead."
+)]
+pub fn count_allocs<F: FnOnce()>(f: F) -> AllocStats {
+    let start = thread_stats();
+    f();
+    thread_stats().since(&start)
+}
+
+/// Get the running totals for the current thread. Must not be called from inside
+/// the allocator without claiming internal allocations.
+pub(crate) fn thread_stats() -> AllocStats {
+    ALLOC_STATS
+        .try_with(|s| *s.read())
+        .unwrap_or(AllocStats::ZERO)
+}
+
+/// Update the running totals for the current thread. Must not be called from inside
+/// the allocator without claiming internal allocations.
+pub(crate) fn track(update: impl FnOnce(&mut AllocStats)) {
+    ALLOC_STATS
+        .try_with(|s| update(&mut s.write()))
+        .unwrap_or(());
+}
@@ -0,0 +1,157 @@
+//! Recording where violations happen, when the `backtrace` feature is enabled
+//!
+//! Walking the stack happens inside the allocator, so frames are stored as raw
+//! instruction pointers in a fixed-size buffer; turning them into symbol names
+//! needs to allocate, and is left until the violation is reported.
+
+use std::fmt;
+
+/// Maximum number of stack frames kept for a single violation
+#[cfg(feature = "backtrace")]
+const MAX_FRAMES: usize = 32;
+
+/// Instruction pointers for the stack at the time of a violation
+#[derive(Clone, Copy)]
+pub(crate) struct Frames {
+    #[cfg(feature = "backtrace")]
+    ips: [usize; MAX_FRAMES],
+    #[cfg(feature = "backtrace")]
+    len: usize,
+}
+
+impl Frames {
+    pub(crate) const EMPTY: Frames = Frames {
+        #[cfg(feature = "backtrace")]
+        ips: [0; MAX_FRAMES],
+        #[cfg(feature = "backtrace")]
+        len: 0,
+    };
+
+    /// Walk the current stack. Must be called after claiming internal allocations,
+    /// so any allocations the unwinder makes pass straight through.
+    #[cfg(feature = "backtrace")]
+    pub(crate) fn capture() -> Frames {
+        let mut frames = Frames::EMPTY;
+        backtrace::trace(|frame| {
+            frames.ips[frames.len] = frame.ip() as usize;
+            frames.len += 1;
+            frames.len < MAX_FRAMES
+        });
+        frames
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    pub(crate) fn capture() -> Frames {
+        Frames::EMPTY
+    }
+
+    #[cfg(feature = "backtrace")]
+    fn ips(&self) -> &[usize] {
+        &self.ips[..self.len]
+    }
+}
+
+impl fmt::Debug for Frames {
+    #[cfg(feature = "backtrace")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.ips()).finish()
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().finish()
+    }
+}
+
+/// Display the frames as plain addresses; safe to use from inside the allocator
+pub(crate) struct Unresolved<'a>(pub(crate) &'a Frames);
+
+impl<'a> fmt::Display for Unresolved<'a> {
+    #[cfg(feature = "backtrace")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len == 0 {
+            return Ok(());
+        }
+        write!(f, "\nAllocation backtrace (unresolved):")?;
+        for (i, ip) in self.0.ips().iter().enumerate() {
+            write!(f, "\n  {:>2}: {:#x}", i, ip)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        let _ = self.0;
+        Ok(())
+    }
+}
+
+/// Whether a frame belongs to the stack walker, QADAPT, or the allocator shims
+/// between QADAPT and the code that made the allocation
+#[cfg(feature = "backtrace")]
+fn is_internal(name: &str) -> bool {
+    const PREFIXES: [&str; 8] = [
+        "backtrace::",
+        "__rust_",
+        "__rustc::",
+        "__rg_",
+        "__rdl_",
+        "alloc::alloc::",
+        "<alloc::alloc::Global",
+        "std::alloc::",
+    ];
+    name.contains("qadapt::") || PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+/// Display the frames with symbol names and source locations. Resolving
+/// symbols allocates, so this must not be used from inside the allocator.
+pub(crate) struct Resolved<'a>(pub(crate) &'a Frames);
+
+impl<'a> fmt::Display for Resolved<'a> {
+    #[cfg(feature = "backtrace")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len == 0 {
+            return Ok(());
+        }
+        write!(f, "\nAllocation backtrace:")?;
+
+        // The first frames belong to the stack walker and QADAPT itself;
+        // start from the first frame outside of them.
+        let mut skipping = true;
+        let mut index = 0;
+        let mut result = Ok(());
+        for &ip in self.0.ips() {
+            let mut resolved = false;
+            backtrace::resolve(ip as *mut _, |symbol| {
+                resolved = true;
+                let name = match symbol.name() {
+                    Some(name) => format!("{:#}", name),
+                    None => format!("{:#x}", ip),
+                };
+                if skipping && is_internal(&name) {
+                    return;
+                }
+                skipping = false;
+
+                result = result.and_then(|_| write!(f, "\n  {:>2}: {}", index, name));
+                if let (Some(file), Some(line)) = (symbol.filename(), symbol.lineno()) {
+                    result =
+                        result.and_then(|_| write!(f, "\n        at {}:{}", file.display(), line));
+                }
+                index += 1;
+            });
+
+            if !resolved && !skipping {
+                result = result.and_then(|_| write!(f, "\n  {:>2}: {:#x}", index, ip));
+                index += 1;
+            }
+        }
+        result
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        let _ = self.0;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
+use qadapt::alloc_budget;
+use qadapt::enter_protected_with_budget;
+use qadapt::exit_protected;
+use qadapt::no_alloc;
+use qadapt::protection_level;
+use qadapt::QADAPT;
+use std::hint::black_box;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[alloc_budget(count = 2)]
+fn two_allocations() {
+    black_box(Box::new(1u8));
+    black_box(Box::new(2u8));
+}
+
+#[test]
+fn within_count() {
+    two_allocations();
+}
+
+#[alloc_budget(count = 2)]
+fn three_allocations() {
+    black_box(Box::new(1u8));
+    black_box(Box::new(2u8));
+    black_box(Box::new(3u8));
+}
+
+#[test]
+fn over_count() {
+    let violation = violation_in(three_allocations);
+    if qadapt::is_active() {
+        let message = violation.unwrap().to_string();
+        assert!(message.contains("allocation budget exceeded: 3 allocations (limit 2)"));
+    }
+}
+
+#[alloc_budget(bytes = 16)]
+fn sized_allocation(size: usize) -> Vec<u8> {
+    Vec::with_capacity(size)
+}
+
+#[test]
+fn within_bytes() {
+    black_box(sized_allocation(16));
+}
+
+#[test]
+fn over_bytes() {
+    let violation = violation_in(|| {
+        black_box(sized_allocation(24));
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.size, 24);
+        assert!(violation.to_string().contains("24 bytes (limit 16)"));
+    }
+}
+
+#[alloc_budget(count = 1, bytes = 64)]
+fn count_and_bytes(b: Box<u32>) {
+    // Freeing memory doesn't count against the budget
+    drop(b);
+    black_box(Vec::<u8>::with_capacity(64));
+}
+
+#[test]
+fn frees_allowed() {
+    count_and_bytes(Box::new(12));
+}
+
+#[alloc_budget(count = 1)]
+fn early_return(b: bool) -> u8 {
+    if b {
+        return 1;
+    }
+    2
+}
+
+#[test]
+fn budget_early_return() {
+    assert_eq!(early_return(true), 1);
+    assert_eq!(early_return(false), 2);
+    assert_eq!(protection_level(), 0);
+}
+
+#[no_alloc]
+fn strict_allocation() {
+    black_box(Box::new(1u8));
+}
+
+#[alloc_budget(count = 4)]
+fn calls_strict() {
+    strict_allocation();
+}
+
+#[test]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
+fn nested_no_alloc() {
+    calls_strict();
+}
+
+#[test]
+fn runtime_budget() {
+    let violation = violation_in(|| {
+        enter_protected_with_budget(Some(1), None);
+        black_box(Box::new(1u8));
+        black_box(Box::new(2u8));
+        exit_protected();
+    });
+    if qadapt::is_active() {
+        let message = violation.unwrap().to_string();
+        assert!(message.contains("allocation budget exceeded"));
+    }
+}
+
+#[alloc_budget(count = 10)]
+fn generous_budget() {
+    black_box(Box::new(1u8));
+}
+
+#[no_alloc]
+fn calls_budget() {
+    generous_budget();
+}
+
+#[test]
+fn budget_inside_no_alloc() {
+    let violation = violation_in(calls_budget);
+    if qadapt::is_active() {
+        let message = violation.unwrap().to_string();
+        assert!(message.contains("calls_budget"));
+        assert!(!message.contains("allocation budget exceeded"));
+    }
+}
+
+#[alloc_budget(count = 1)]
+fn calls_two_budgets() {
+    two_allocations();
+}
+
+#[test]
+fn outer_budget_charged() {
+    let violation = violation_in(calls_two_budgets);
+    if qadapt::is_active() {
+        let message = violation.unwrap().to_string();
+        assert!(message.contains("calls_two_budgets"));
+        assert!(message.contains("allocation budget exceeded"));
+    }
+}
@@ -1,7 +1,15 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
 use qadapt::enter_protected;
 use qadapt::exit_protected;
 use qadapt::protection_level;
+use qadapt::ViolationKind;
 use qadapt::QADAPT;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
 
 #[global_allocator]
 static Q: QADAPT = QADAPT;
@@ -15,7 +23,7 @@ fn test_copy() {
 }
 
 #[test]
-#[cfg_attr(debug_assertions, should_panic)]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
 fn test_allocate() {
     enter_protected();
     let _x = Box::new(12);
@@ -44,11 +52,12 @@ fn vec_push() {
     let mut v = Vec::new();
     enter_protected();
     v.push(0);
-    // We don't make it here in debug mode, but in release mode,
-    // pushing one element doesn't trigger an allocation. Instead,
-    // we use a box to force it onto the heap
+    // In release mode, pushing one element doesn't trigger an allocation,
+    // and the protection level is always 0. Instead, we use a box
+    // to force it onto the heap
     assert_eq!(protection_level(), 1);
     let _b = Box::new(v);
+    exit_protected();
 }
 
 #[test]
@@ -80,15 +89,16 @@ fn vec_new() {
 fn vec_with_one() {
     enter_protected();
     let v: Vec<u8> = Vec::with_capacity(1);
-    // We don't make it here in debug mode, but in release mode,
-    // pushing one element doesn't trigger an allocation. Instead,
-    // we use a box to force it onto the heap
+    // In release mode, allocating for one element can be optimized out,
+    // and the protection level is always 0. Instead, we use a box
+    // to force it onto the heap
     assert_eq!(protection_level(), 1);
     let _b = Box::new(v);
+    exit_protected();
 }
 
 #[test]
-#[cfg_attr(debug_assertions, should_panic)]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
 fn exit_too_often() {
     enter_protected();
     exit_protected();
@@ -96,10 +106,113 @@ fn exit_too_often() {
 }
 
 #[test]
-#[cfg_attr(debug_assertions, should_panic)]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
 fn intentional_drop() {
     let v: Vec<()> = Vec::new();
     let v = Box::new(v);
     enter_protected();
     drop(v);
+    exit_protected();
+}
+
+#[test]
+fn zeroed_allocate() {
+    let violation = violation_in(|| {
+        enter_protected();
+        let _v = std::hint::black_box(vec![0u8; 16]);
+        exit_protected();
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.kind, ViolationKind::ZeroedAllocation);
+        assert_eq!(violation.size, 16);
+        assert!(violation
+            .to_string()
+            .starts_with("Unexpected zeroed allocation for size 16"));
+    }
+}
+
+#[test]
+fn vec_grow() {
+    let violation = violation_in(|| {
+        let mut v: Vec<u8> = Vec::with_capacity(16);
+        enter_protected();
+        v.reserve_exact(32);
+        exit_protected();
+        std::hint::black_box(v);
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.kind, ViolationKind::Reallocation { new_size: 32 });
+        assert_eq!(violation.size, 16);
+        assert!(violation
+            .to_string()
+            .starts_with("Unexpected reallocation from 16 to 32 bytes"));
+    }
+}
+
+#[test]
+fn deferred_panic() {
+    let mut reached = false;
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        enter_protected();
+        let _b = std::hint::black_box(Box::new(12));
+        // The allocation itself succeeds, and the panic waits until we exit
+        reached = true;
+        exit_protected();
+    }));
+
+    assert!(reached);
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        assert!(res.is_err());
+        assert_eq!(protection_level(), 0);
+    }
+}
+
+#[test]
+fn deferred_panic_nested() {
+    let line = line!() + 2;
+    let violation = violation_in(|| {
+        enter_protected();
+        enter_protected();
+        let _b = std::hint::black_box(Box::new(12u32));
+        // Reported at the first exit after the allocation
+        exit_protected();
+        exit_protected();
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.protection_level, 2);
+        // With the `backtrace` feature, the frames follow on the next lines
+        assert_eq!(
+            violation.to_string().lines().next().unwrap(),
+            format!(
+                "Unexpected allocation for size 4 inside enter_protected() (tests/allocations.rs:{}), \
+                 within enter_protected() (tests/allocations.rs:{}), protection level: 2",
+                line + 1,
+                line
+            )
+        );
+    }
+}
+
+#[test]
+fn deferred_panic_multiple() {
+    let violation = violation_in(|| {
+        enter_protected();
+        let b = std::hint::black_box(Box::new(12));
+        drop(b);
+        exit_protected();
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.kind, ViolationKind::Allocation);
+        assert_eq!(violation.others, 1);
+        assert!(violation
+            .to_string()
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with("(and 1 more in this region)"));
+    }
 }
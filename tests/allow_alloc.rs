@@ -0,0 +1,163 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::expected_level;
+use common::violation_in;
+use qadapt::allow_alloc;
+use qadapt::allow_allocations;
+use qadapt::assert_no_alloc;
+use qadapt::current_regions;
+use qadapt::no_alloc;
+use qadapt::protect;
+use qadapt::protect_named;
+use qadapt::protection_level;
+use qadapt::ViolationKind;
+use qadapt::QADAPT;
+use std::hint::black_box;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[test]
+fn allow_macro() {
+    let b = assert_no_alloc!({
+        let x = 2 + 2;
+        allow_alloc!(black_box(Box::new(x)))
+    });
+    assert_eq!(*b, 4);
+}
+
+#[test]
+fn allow_restores_level() {
+    let _outer = protect();
+    let _inner = protect();
+    {
+        let _allow = allow_allocations();
+        assert_eq!(protection_level(), 0);
+        drop(black_box(Box::new(12)));
+    }
+    assert_eq!(protection_level(), expected_level(2));
+}
+
+#[test]
+fn nested_allow() {
+    let _guard = protect();
+    allow_alloc!({
+        allow_alloc!(drop(black_box(Box::new(1))));
+        drop(black_box(Box::new(2)));
+    });
+    assert_eq!(protection_level(), expected_level(1));
+}
+
+#[test]
+fn protect_dropped_while_allowed() {
+    let guard = protect_named("dropped");
+    let allow = allow_allocations();
+    drop(guard);
+    drop(allow);
+    assert_eq!(protection_level(), 0);
+    assert_eq!(current_regions().next(), None);
+}
+
+#[test]
+fn allow_dropped_while_protected() {
+    let outer = protect_named("outer");
+    let allow = allow_allocations();
+    let inner = protect_named("inner");
+    drop(allow);
+    assert_eq!(protection_level(), expected_level(2));
+    drop(inner);
+    drop(outer);
+    assert_eq!(protection_level(), 0);
+    assert_eq!(current_regions().next(), None);
+}
+
+#[test]
+fn protect_inside_allow() {
+    let violation = violation_in(|| {
+        let _guard = protect();
+        allow_alloc!({
+            // Regions entered while allowed are still checked
+            assert_no_alloc!(drop(black_box(Box::new(1))));
+        });
+    });
+    if qadapt::is_active() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Allocation);
+    }
+}
+
+#[no_alloc]
+fn checked_div(x: u32, y: u32) -> Result<u32, String> {
+    if y == 0 {
+        #[allow_alloc]
+        return Err(format!("Can't divide {} by zero", x));
+    }
+    Ok(x / y)
+}
+
+#[test]
+fn allow_statement_return() {
+    assert_eq!(checked_div(12, 4), Ok(3));
+    assert!(checked_div(12, 0).is_err());
+    assert_eq!(protection_level(), 0);
+}
+
+#[no_alloc]
+fn allow_let(v: &[u8]) -> usize {
+    #[allow_alloc]
+    let b = black_box(Box::new(v.len()));
+    let len = *b;
+    #[allow_alloc]
+    drop(b);
+    len
+}
+
+#[test]
+fn allow_statement_let() {
+    assert_eq!(allow_let(&[]), 0);
+}
+
+#[no_alloc]
+fn allow_block(v: &mut Vec<u8>, grow: bool) -> usize {
+    #[allow_alloc]
+    if grow {
+        v.reserve(64);
+    } else {
+        v.shrink_to_fit();
+    }
+    v.capacity()
+}
+
+#[test]
+fn allow_statement_block() {
+    let mut v = Vec::new();
+    assert!(allow_block(&mut v, true) >= 64);
+    assert_eq!(allow_block(&mut v, false), 0);
+}
+
+#[no_alloc]
+fn allow_tail(x: u8) -> Vec<u8> {
+    #[allow_alloc]
+    vec![x; 4]
+}
+
+#[test]
+fn allow_statement_tail() {
+    assert_eq!(allow_tail(1), vec![1; 4]);
+}
+
+#[no_alloc]
+fn allow_only_marked(v: &mut Vec<u8>) {
+    #[allow_alloc]
+    v.reserve(16);
+    black_box(Box::new(1));
+}
+
+#[test]
+fn allow_statement_scope() {
+    let violation = violation_in(|| allow_only_marked(&mut Vec::new()));
+    if qadapt::is_active() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Allocation);
+    }
+}
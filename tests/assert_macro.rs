@@ -1,3 +1,5 @@
+#![allow(deprecated, clippy::diverging_sub_expression)]
+
 use qadapt::assert_no_alloc;
 use qadapt::QADAPT;
 
@@ -10,22 +12,17 @@ fn math() {
     assert_eq!(x, 4);
 }
 
-// Because the `exit_protected` guard is never run, the compiler
-// warns us of unreachable code
+// The compiler warns us that exiting the protected region is unreachable,
+// but the guard still cleans up
 #[allow(unreachable_code)]
 fn early_return() -> usize {
     assert_no_alloc!(return 8)
 }
 
 #[test]
-#[should_panic]
 fn early_return_boxing() {
-    // The release-mode compiler is able to optimize through the Box
-    if cfg!(debug_assertions) {
-        Box::new(early_return());
-    } else {
-        panic!("Intentional")
-    }
+    let _b = Box::new(early_return());
+    assert_eq!(::qadapt::protection_level(), 0);
 }
 
 #[test]
@@ -34,7 +31,7 @@ fn list_push() {
     let mut x = Vec::with_capacity(1);
     x.push(1);
 
-    if cfg!(debug_assertions) {
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
         assert_no_alloc!(x.push(12))
     } else {
         panic!("Intentional")
@@ -1,8 +1,19 @@
+#![allow(deprecated, unused_must_use)]
+
+mod common;
+
+use common::noop_waker;
 use futures::future::ok;
 use futures::prelude::*;
 use qadapt::assert_no_alloc;
+use qadapt::current_regions;
 use qadapt::no_alloc;
+use qadapt::protection_level;
 use qadapt::QADAPT;
+use std::future::Future as StdFuture;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
 
 #[global_allocator]
 static Q: QADAPT = QADAPT;
@@ -25,9 +36,172 @@ fn guarded_call() {
 #[test]
 #[should_panic]
 fn guarded_poll() {
-    if cfg!(debug_assertions) {
-        assert_no_alloc!(async_box().poll().unwrap());
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        assert_no_alloc!(std::hint::black_box(async_box().poll().unwrap()));
+    } else {
+        panic!("Intentional")
+    }
+}
+
+/// Future that isn't ready the first time it's polled
+struct YieldOnce(bool);
+
+impl StdFuture for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+fn poll_once<F: StdFuture>(f: Pin<&mut F>) -> Poll<F::Output> {
+    f.poll(&mut Context::from_waker(&noop_waker()))
+}
+
+/// Poll a future to completion, checking that it isn't protected while suspended
+fn block_on<F: StdFuture>(f: F) -> F::Output {
+    let mut f = Box::pin(f);
+    loop {
+        if let Poll::Ready(v) = poll_once(f.as_mut()) {
+            return v;
+        }
+        assert_eq!(protection_level(), 0);
+        std::hint::black_box(Box::new(1u8));
+    }
+}
+
+#[no_alloc]
+async fn add_after_yield(x: u32, y: u32) -> u32 {
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        assert_eq!(protection_level(), 1);
+    }
+    YieldOnce(false).await;
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        assert_eq!(protection_level(), 1);
+    }
+    x + y
+}
+
+fn assert_send<T: Send>(t: T) -> T {
+    t
+}
+
+#[test]
+fn async_fn_protects_polls() {
+    // Protection doesn't stop the future from moving between threads
+    assert_eq!(block_on(assert_send(add_after_yield(1, 2))), 3);
+    assert_eq!(protection_level(), 0);
+}
+
+#[no_alloc]
+async fn allocates_after_yield() -> Box<u8> {
+    YieldOnce(false).await;
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn async_fn_creation() {
+    let f = assert_no_alloc!(allocates_after_yield());
+    drop(f);
+}
+
+#[test]
+#[should_panic]
+fn async_fn_allocates() {
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        block_on(allocates_after_yield());
+    } else {
+        panic!("Intentional")
+    }
+}
+
+#[no_alloc]
+async fn parse_after_yield(s: &str) -> Result<u8, std::num::ParseIntError> {
+    YieldOnce(false).await;
+    let x: u8 = s.parse()?;
+    Ok(x * 2)
+}
+
+#[test]
+fn async_fn_try() {
+    assert_eq!(block_on(parse_after_yield("4")), Ok(8));
+    assert!(block_on(parse_after_yield("four")).is_err());
+    assert_eq!(protection_level(), 0);
+}
+
+#[no_alloc]
+fn impl_future_allocates() -> impl std::future::Future<Output = Box<u8>> {
+    async {
+        YieldOnce(false).await;
+        std::hint::black_box(Box::new(12))
+    }
+}
+
+#[test]
+#[should_panic]
+fn impl_future_poll() {
+    if cfg!(any(debug_assertions, feature = "release-checks")) {
+        let f = impl_future_allocates();
+        block_on(f);
     } else {
         panic!("Intentional")
     }
 }
+
+#[test]
+fn impl_future_suspended() {
+    let mut f = Box::pin(impl_future_allocates());
+    assert!(poll_once(f.as_mut()).is_pending());
+    assert_eq!(protection_level(), 0);
+}
+
+#[no_alloc(policy = "count", name = "async_named")]
+async fn counted_after_yield() -> Box<u8> {
+    YieldOnce(false).await;
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn async_fn_options() {
+    assert_eq!(*block_on(counted_after_yield()), 12);
+    assert_eq!(qadapt::region_stats("async_named").allocations, 1);
+    if qadapt::is_active() {
+        assert_eq!(qadapt::region_violation_count("async_named"), 1);
+    }
+}
+
+#[no_alloc]
+async fn allow_between_yields(x: u8) -> Box<u8> {
+    YieldOnce(false).await;
+    #[allow_alloc]
+    let b = std::hint::black_box(Box::new(x));
+    YieldOnce(false).await;
+    b
+}
+
+#[test]
+fn async_fn_allow_statement() {
+    assert_eq!(*block_on(assert_send(allow_between_yields(3))), 3);
+    assert_eq!(protection_level(), 0);
+    assert_eq!(current_regions().next(), None);
+}
+
+#[no_alloc]
+fn early_return_future(early: bool) -> impl std::future::Future<Output = u32> {
+    if early {
+        return std::future::ready(1);
+    }
+    std::future::ready(2)
+}
+
+#[test]
+fn impl_future_early_return() {
+    assert_eq!(block_on(early_return_future(true)), 1);
+    assert_eq!(block_on(early_return_future(false)), 2);
+    assert_eq!(protection_level(), 0);
+}
@@ -0,0 +1,61 @@
+#![cfg(feature = "backtrace")]
+#![allow(deprecated)]
+
+use qadapt::enter_protected;
+use qadapt::exit_protected;
+use qadapt::AllocationViolation;
+use qadapt::QADAPT;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[inline(never)]
+fn allocating_function() -> Box<u32> {
+    std::hint::black_box(Box::new(12))
+}
+
+fn panic_message() -> Option<String> {
+    let err = catch_unwind(|| {
+        enter_protected();
+        let _b = allocating_function();
+        exit_protected();
+    })
+    .err()?;
+
+    let violation = err.downcast::<AllocationViolation>().unwrap();
+    // The backtrace is part of the panic payload's message
+    Some(violation.to_string())
+}
+
+#[test]
+fn backtrace_in_message() {
+    let message = panic_message();
+    if !qadapt::is_active() {
+        assert_eq!(message, None);
+        return;
+    }
+
+    let message = message.unwrap();
+    assert!(message.starts_with("Unexpected allocation for size 4"));
+    assert!(message.contains("Allocation backtrace:"));
+    assert!(message.contains("allocating_function"));
+}
+
+#[test]
+fn backtrace_skips_qadapt_frames() {
+    let message = match panic_message() {
+        Some(m) => m,
+        None => return,
+    };
+
+    let first_frame = message
+        .lines()
+        .skip_while(|l| !l.starts_with("Allocation backtrace:"))
+        .nth(1)
+        .unwrap();
+    assert!(!first_frame.contains("qadapt::"), "{}", first_frame);
+    // Nor to the allocator shims that call into QADAPT
+    assert!(!first_frame.contains("__rust"), "{}", first_frame);
+    assert!(!first_frame.contains("alloc::alloc::"), "{}", first_frame);
+}
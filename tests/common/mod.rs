@@ -0,0 +1,63 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use qadapt::AllocationViolation;
+use std::panic::catch_unwind;
+use std::panic::UnwindSafe;
+use std::ptr;
+#[cfg(unix)]
+use std::sync::atomic::AtomicUsize;
+#[cfg(unix)]
+use std::sync::atomic::Ordering;
+use std::task::RawWaker;
+use std::task::RawWakerVTable;
+use std::task::Waker;
+
+/// Run `f`, returning the violation it panicked with, if any
+pub fn violation_in<F: FnOnce() + UnwindSafe>(f: F) -> Option<AllocationViolation> {
+    let err = catch_unwind(f).err()?;
+    Some(*err.downcast::<AllocationViolation>().unwrap())
+}
+
+/// The protection level to expect after entering `level` regions; QADAPT doesn't
+/// track regions when it isn't active
+pub fn expected_level(level: usize) -> usize {
+    if qadapt::is_active() {
+        level
+    } else {
+        0
+    }
+}
+
+const NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+fn noop_clone(_: *const ()) -> RawWaker {
+    RawWaker::new(ptr::null(), &NOOP_VTABLE)
+}
+
+fn noop(_: *const ()) {}
+
+/// A waker that does nothing, for polling futures by hand. Creating it
+/// doesn't allocate, so it's fine to use inside protected regions.
+pub fn noop_waker() -> Waker {
+    unsafe { Waker::from_raw(noop_clone(ptr::null())) }
+}
+
+/// Catch `SIGTRAP` in place of a debugger, so trapping doesn't end the test.
+/// Returns the number of times it's been raised.
+#[cfg(unix)]
+pub fn count_traps() -> &'static AtomicUsize {
+    static TRAPS: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn on_trap(_: libc::c_int) {
+        TRAPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(
+            libc::SIGTRAP,
+            on_trap as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    &TRAPS
+}
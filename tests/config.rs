@@ -0,0 +1,224 @@
+#![allow(deprecated)]
+
+mod common;
+
+#[cfg(unix)]
+use common::count_traps;
+use qadapt::enter_protected;
+use qadapt::exit_protected;
+use qadapt::violation_count;
+use qadapt::violation_policy;
+use qadapt::AllocationViolation;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::env;
+use std::fs;
+use std::panic::catch_unwind;
+use std::process::Command;
+use std::process::Output;
+use std::sync::atomic::Ordering;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+/// QADAPT only reads the environment at the first allocation, so each test below
+/// runs again in a new process with the variables set
+fn output_in_child(test: &str, vars: &[(&str, &str)]) -> Output {
+    Command::new(env::current_exe().unwrap())
+        .args([test, "--exact", "--test-threads=1"])
+        .env("QADAPT_CONFIG_CHILD", "1")
+        .envs(vars.iter().cloned())
+        .output()
+        .unwrap()
+}
+
+fn run_in_child(test: &str, vars: &[(&str, &str)]) {
+    stderr_in_child(test, vars);
+}
+
+/// Like [`run_in_child`], but collect what the test wrote to stderr
+fn stderr_in_child(test: &str, vars: &[(&str, &str)]) -> String {
+    let output = output_in_child(test, vars);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{}", stderr);
+    stderr
+}
+
+fn in_child() -> bool {
+    env::var_os("QADAPT_CONFIG_CHILD").is_some()
+}
+
+#[test]
+fn mode() {
+    if !in_child() {
+        return run_in_child("mode", &[("QADAPT_MODE", "Count")]);
+    }
+
+    assert_eq!(violation_policy(), ViolationPolicy::Count);
+    enter_protected();
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+
+    if qadapt::is_active() {
+        assert_eq!(violation_count(), 1);
+    }
+}
+
+#[test]
+fn log_mode_fails_at_exit() {
+    if !in_child() {
+        let output = output_in_child("log_mode_fails_at_exit", &[("QADAPT_MODE", "log")]);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        // The test itself passes, but the logged violation still fails the run
+        assert!(stdout.contains("test result: ok"), "{}", stdout);
+        if qadapt::is_active() {
+            assert!(!output.status.success());
+            assert!(stderr.contains("1 violations were logged"), "{}", stderr);
+        } else {
+            assert!(output.status.success(), "{}", stderr);
+        }
+        return;
+    }
+
+    enter_protected();
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+}
+
+#[test]
+fn unknown_mode() {
+    if !in_child() {
+        return run_in_child("unknown_mode", &[("QADAPT_MODE", "explode")]);
+    }
+
+    assert_eq!(violation_policy(), ViolationPolicy::Panic);
+}
+
+#[test]
+fn immediate() {
+    if !in_child() {
+        return run_in_child("immediate", &[("QADAPT_IMMEDIATE", "1")]);
+    }
+
+    let res = catch_unwind(|| {
+        enter_protected();
+        let _b = std::hint::black_box(Box::new(12));
+        panic!("Reached the end of the region");
+    });
+
+    // Immediate panics are only supported in debug builds
+    if qadapt::is_active() && cfg!(debug_assertions) {
+        assert!(res.unwrap_err().is::<AllocationViolation>());
+    }
+}
+
+#[test]
+fn disable() {
+    if !in_child() {
+        return run_in_child("disable", &[("QADAPT_DISABLE", "yes")]);
+    }
+
+    assert!(!qadapt::is_active());
+    enter_protected();
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+}
+
+#[test]
+#[cfg(unix)]
+fn report() {
+    if !in_child() {
+        let path = env::temp_dir().join(format!("qadapt-report-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let output = output_in_child(
+            "report",
+            &[
+                ("QADAPT_MODE", "log"),
+                ("QADAPT_REPORT", path.to_str().unwrap()),
+            ],
+        );
+        // Logging still fails the run at exit, so only check that the test passed
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("test result: ok"), "{}", stdout);
+        return;
+    }
+
+    let path = env::var("QADAPT_REPORT").unwrap();
+    enter_protected();
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+
+    let report = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    if qadapt::is_active() {
+        assert!(report.starts_with("qadapt: Unexpected allocation for size 4"));
+    } else {
+        assert!(report.is_empty());
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn trap() {
+    if !in_child() {
+        return run_in_child("trap", &[("QADAPT_TRAP", "1"), ("QADAPT_MODE", "count")]);
+    }
+
+    // Stand in for a debugger, so the trap doesn't end the test
+    let traps = count_traps();
+    enter_protected();
+    let b = std::hint::black_box(Box::new(12));
+    exit_protected();
+    drop(b);
+
+    if qadapt::is_active() {
+        assert_eq!(traps.load(Ordering::SeqCst), 1);
+    } else {
+        assert_eq!(traps.load(Ordering::SeqCst), 0);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn unknown_flag() {
+    if !in_child() {
+        let stderr = stderr_in_child(
+            "unknown_flag",
+            &[("QADAPT_TRAP", "maybe"), ("QADAPT_MODE", "count")],
+        );
+        assert!(stderr.contains("qadapt: ignoring unknown QADAPT_TRAP value \"maybe\""));
+        return;
+    }
+
+    // The unknown value leaves trapping turned off
+    let traps = count_traps();
+    enter_protected();
+    let b = std::hint::black_box(Box::new(12));
+    exit_protected();
+    drop(b);
+
+    assert_eq!(traps.load(Ordering::SeqCst), 0);
+    if qadapt::is_active() {
+        assert_eq!(violation_count(), 1);
+    }
+}
+
+#[test]
+fn no_backtrace() {
+    if !in_child() {
+        return run_in_child("no_backtrace", &[("QADAPT_BACKTRACE", "0")]);
+    }
+
+    let res = catch_unwind(|| {
+        enter_protected();
+        let _b = std::hint::black_box(Box::new(12));
+        exit_protected();
+    });
+
+    // Even with the `backtrace` feature, no stack is recorded
+    if qadapt::is_active() {
+        let violation = res.unwrap_err().downcast::<AllocationViolation>().unwrap();
+        assert_eq!(violation.backtrace().to_string(), "");
+    }
+}
@@ -0,0 +1,68 @@
+#![allow(deprecated)]
+
+use qadapt::count_allocs;
+use qadapt::AllocStats;
+use qadapt::QADAPT;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[test]
+fn no_allocations() {
+    let stats = count_allocs(|| {
+        let v = 0u8;
+        let _v2 = v;
+    });
+    assert_eq!(stats, AllocStats::default());
+}
+
+#[test]
+fn box_allocation() {
+    let stats = count_allocs(|| {
+        let b = std::hint::black_box(Box::new(12u32));
+        drop(b);
+    });
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.deallocations, 1);
+    assert_eq!(stats.bytes_allocated, 4);
+    assert_eq!(stats.bytes_deallocated, 4);
+}
+
+#[test]
+fn zeroed_allocation() {
+    let stats = count_allocs(|| {
+        std::hint::black_box(vec![0u8; 16]);
+    });
+    assert_eq!(stats.allocations, 0);
+    assert_eq!(stats.zeroed_allocations, 1);
+    assert_eq!(stats.bytes_allocated, 16);
+}
+
+#[test]
+fn vec_growth() {
+    let mut v: Vec<u8> = Vec::with_capacity(16);
+    let stats = count_allocs(|| {
+        v.reserve_exact(32);
+    });
+    std::hint::black_box(v);
+
+    assert_eq!(stats.allocations, 0);
+    assert_eq!(stats.reallocations, 1);
+    assert_eq!(stats.bytes_allocated, 32);
+    assert_eq!(stats.bytes_deallocated, 16);
+}
+
+#[test]
+fn nested_counts() {
+    let mut inner = AllocStats::default();
+    let outer = count_allocs(|| {
+        let _a = std::hint::black_box(Box::new(1u8));
+        inner = count_allocs(|| {
+            let _b = std::hint::black_box(Box::new(2u8));
+        });
+    });
+
+    assert_eq!(inner.allocations, 1);
+    assert_eq!(outer.allocations, 2);
+    assert_eq!(outer.deallocations, 2);
+}
@@ -0,0 +1,75 @@
+#![allow(deprecated)]
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use qadapt::enter_protected;
+use qadapt::exit_protected;
+use qadapt::QADAPT;
+
+static INNER_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static INNER_DEALLOCS: AtomicUsize = AtomicUsize::new(0);
+static INNER_REALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INNER_ALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        INNER_DEALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        INNER_REALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static Q: QADAPT<CountingAlloc> = QADAPT::wrap(CountingAlloc);
+
+#[test]
+fn forwards_to_inner() {
+    let allocs = INNER_ALLOCS.load(Ordering::SeqCst);
+    let deallocs = INNER_DEALLOCS.load(Ordering::SeqCst);
+
+    drop(std::hint::black_box(Box::new(12)));
+
+    assert!(INNER_ALLOCS.load(Ordering::SeqCst) > allocs);
+    assert!(INNER_DEALLOCS.load(Ordering::SeqCst) > deallocs);
+}
+
+#[test]
+fn forwards_realloc_to_inner() {
+    let reallocs = INNER_REALLOCS.load(Ordering::SeqCst);
+
+    let mut v: Vec<u8> = Vec::with_capacity(16);
+    v.reserve_exact(32);
+    std::hint::black_box(v);
+
+    assert!(INNER_REALLOCS.load(Ordering::SeqCst) > reallocs);
+}
+
+#[test]
+fn protected_no_allocate() {
+    enter_protected();
+    let v = 0u8;
+    let _v2 = v;
+    exit_protected();
+}
+
+#[test]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
+fn protected_allocate() {
+    enter_protected();
+    let _x = std::hint::black_box(Box::new(12));
+    exit_protected();
+}
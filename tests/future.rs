@@ -0,0 +1,163 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::noop_waker;
+use common::violation_in;
+use qadapt::future::NoAllocFutureExt;
+use qadapt::protection_level;
+use qadapt::QADAPT;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+/// Future that isn't ready the first time it's polled
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+fn poll_once<F: Future>(f: Pin<&mut F>) -> Poll<F::Output> {
+    f.poll(&mut Context::from_waker(&noop_waker()))
+}
+
+async fn sum_after_yield(v: &[u32]) -> u32 {
+    YieldOnce(false).await;
+    v.iter().sum()
+}
+
+async fn allocate_after_yield() -> Box<u32> {
+    YieldOnce(false).await;
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn future_not_protected_while_suspended() {
+    let v = [1, 2, 3];
+    let mut f = Box::pin(sum_after_yield(&v).no_alloc());
+
+    assert!(poll_once(f.as_mut()).is_pending());
+    assert_eq!(protection_level(), 0);
+    let _b = std::hint::black_box(Box::new(1u8));
+
+    assert_eq!(poll_once(f.as_mut()), Poll::Ready(6));
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn future_allocation_names_region() {
+    let mut f = Box::pin(allocate_after_yield().no_alloc_named("allocating_task"));
+    assert!(poll_once(f.as_mut()).is_pending());
+
+    let violation = violation_in(AssertUnwindSafe(|| {
+        let _ = poll_once(f.as_mut());
+    }));
+
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.region, Some("allocating_task"));
+        assert!(violation
+            .to_string()
+            .contains("inside region \"allocating_task\" (tests/future.rs:"));
+    } else {
+        assert!(violation.is_none());
+    }
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn future_into_inner() {
+    let f = YieldOnce(true).no_alloc().into_inner();
+    assert!(f.0);
+}
+
+#[cfg(feature = "futures-core")]
+mod stream {
+    use super::noop_waker;
+    use super::violation_in;
+    use futures_core::Stream;
+    use qadapt::future::NoAllocStreamExt;
+    use qadapt::protection_level;
+    use std::panic::AssertUnwindSafe;
+    use std::pin::Pin;
+    use std::task::Context;
+    use std::task::Poll;
+
+    /// Counts up to a limit, allocating once it gets there if asked to
+    struct Counter {
+        next: u32,
+        limit: u32,
+        allocate: bool,
+    }
+
+    impl Stream for Counter {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<u32>> {
+            if self.next == self.limit {
+                if self.allocate {
+                    std::hint::black_box(Box::new(self.next));
+                }
+                return Poll::Ready(None);
+            }
+            self.next += 1;
+            Poll::Ready(Some(self.next))
+        }
+    }
+
+    fn poll_next<S: Stream>(s: Pin<&mut S>) -> Poll<Option<S::Item>> {
+        s.poll_next(&mut Context::from_waker(&noop_waker()))
+    }
+
+    #[test]
+    fn stream_no_alloc() {
+        let counter = Counter {
+            next: 0,
+            limit: 2,
+            allocate: false,
+        };
+        let mut s = Box::pin(counter.no_alloc());
+
+        assert_eq!(poll_next(s.as_mut()), Poll::Ready(Some(1)));
+        assert_eq!(protection_level(), 0);
+        assert_eq!(poll_next(s.as_mut()), Poll::Ready(Some(2)));
+        assert_eq!(poll_next(s.as_mut()), Poll::Ready(None));
+    }
+
+    #[test]
+    fn stream_allocation_names_region() {
+        let counter = Counter {
+            next: 0,
+            limit: 1,
+            allocate: true,
+        };
+        let mut s = Box::pin(counter.no_alloc_named("counter"));
+        assert_eq!(poll_next(s.as_mut()), Poll::Ready(Some(1)));
+
+        let violation = violation_in(AssertUnwindSafe(|| {
+            let _ = poll_next(s.as_mut());
+        }));
+
+        if qadapt::is_active() {
+            assert_eq!(violation.unwrap().region, Some("counter"));
+        } else {
+            assert!(violation.is_none());
+        }
+        assert_eq!(protection_level(), 0);
+    }
+}
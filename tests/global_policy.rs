@@ -0,0 +1,37 @@
+#![allow(deprecated)]
+
+use qadapt::assert_no_alloc;
+use qadapt::enter_protected_with_policy;
+use qadapt::exit_protected;
+use qadapt::set_violation_policy;
+use qadapt::violation_count;
+use qadapt::violation_policy;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[test]
+fn global_log() {
+    set_violation_policy(ViolationPolicy::Log);
+    assert_eq!(violation_policy(), ViolationPolicy::Log);
+
+    let before = violation_count();
+    let b = std::hint::black_box(Box::new(12));
+    assert_no_alloc!(drop(b));
+
+    if qadapt::is_active() {
+        assert!(violation_count() > before);
+    }
+}
+
+#[test]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
+fn region_overrides_global() {
+    set_violation_policy(ViolationPolicy::Log);
+
+    enter_protected_with_policy(ViolationPolicy::Panic);
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+}
@@ -0,0 +1,94 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::expected_level;
+use qadapt::protect;
+use qadapt::protect_with_policy;
+use qadapt::protection_level;
+use qadapt::AllocationViolation;
+use qadapt::ViolationKind;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::hint::black_box;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[test]
+fn guard_scope() {
+    {
+        let _guard = protect();
+        assert_eq!(protection_level(), expected_level(1));
+    }
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn nested_guards() {
+    let outer = protect();
+    let inner = protect();
+    assert_eq!(protection_level(), expected_level(2));
+    drop(inner);
+    assert_eq!(protection_level(), expected_level(1));
+    drop(outer);
+    assert_eq!(protection_level(), 0);
+}
+
+fn parse_protected(input: &str) -> Result<u32, std::num::ParseIntError> {
+    let _guard = protect();
+    let x: u32 = input.parse()?;
+    Ok(x)
+}
+
+#[test]
+fn question_mark_exit() {
+    assert!(parse_protected("not a number").is_err());
+    assert_eq!(protection_level(), 0);
+    assert_eq!(parse_protected("12"), Ok(12));
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn panic_exit() {
+    let res = catch_unwind(|| {
+        let _guard = protect();
+        panic!("Intentional")
+    });
+    assert!(res.is_err());
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn guard_reports_violation() {
+    let res = catch_unwind(|| {
+        let _guard = protect();
+        black_box(Box::new(12));
+    });
+    if qadapt::is_active() {
+        let err = res.unwrap_err();
+        let violation = err.downcast_ref::<AllocationViolation>().unwrap();
+        assert_eq!(violation.kind, ViolationKind::Allocation);
+        assert_eq!(violation.size, 4);
+    }
+}
+
+#[test]
+fn violation_exit() {
+    let res = catch_unwind(|| {
+        let _outer = protect();
+        let _inner = protect();
+        black_box(Box::new(12));
+    });
+    if qadapt::is_active() {
+        assert!(res.is_err());
+    }
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn guard_policy() {
+    let _guard = protect_with_policy(ViolationPolicy::Ignore);
+    black_box(Box::new(12));
+}
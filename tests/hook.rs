@@ -0,0 +1,107 @@
+#![allow(deprecated)]
+
+use qadapt::enter_protected_named;
+use qadapt::exit_protected;
+use qadapt::protect_with_policy;
+use qadapt::set_violation_hook;
+use qadapt::take_violation_hook;
+use qadapt::try_no_alloc;
+use qadapt::AllocationViolation;
+use qadapt::ViolationKind;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::hint::black_box;
+use std::panic::catch_unwind;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+static SUPPRESSED: AtomicUsize = AtomicUsize::new(0);
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+static IGNORED: AtomicUsize = AtomicUsize::new(0);
+static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// The hook is global, so tests can't run while another one has removed it
+static HOOK_LOCK: Mutex<()> = Mutex::new(());
+
+fn hook(violation: &AllocationViolation) -> bool {
+    match violation.region {
+        Some("suppressed") => {
+            SUPPRESSED.fetch_add(1, Ordering::SeqCst);
+            false
+        }
+        Some("reported") => {
+            REPORTED.fetch_add(1, Ordering::SeqCst);
+            // Allocating inside the hook is allowed
+            MESSAGES.lock().unwrap().push(violation.to_string());
+            true
+        }
+        _ => {
+            IGNORED.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+    }
+}
+
+#[test]
+fn hook_suppresses_panic() {
+    let _lock = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_violation_hook(hook);
+
+    enter_protected_named("suppressed");
+    let _b = black_box(Box::new(12u32));
+    exit_protected();
+
+    if qadapt::is_active() {
+        assert_eq!(SUPPRESSED.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[test]
+fn hook_keeps_panic() {
+    let _lock = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_violation_hook(hook);
+
+    let res = catch_unwind(|| {
+        enter_protected_named("reported");
+        let _b = black_box(Box::new(12u32));
+        exit_protected();
+    });
+
+    if qadapt::is_active() {
+        let err = res.unwrap_err();
+        let violation = err.downcast_ref::<AllocationViolation>().unwrap();
+        assert_eq!(violation.kind, ViolationKind::Allocation);
+        assert_eq!(REPORTED.load(Ordering::SeqCst), 1);
+
+        let messages = MESSAGES.lock().unwrap();
+        assert!(messages[0].starts_with("Unexpected allocation for size 4"));
+    }
+}
+
+#[test]
+fn hook_skipped_for_ignored_and_captured() {
+    let _lock = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_violation_hook(hook);
+    let before = IGNORED.load(Ordering::SeqCst);
+
+    {
+        let _guard = protect_with_policy(ViolationPolicy::Ignore);
+        black_box(Box::new(12u32));
+    }
+    let _ = try_no_alloc(|| black_box(Box::new(12u32)));
+
+    assert_eq!(IGNORED.load(Ordering::SeqCst), before);
+}
+
+#[test]
+fn take_hook() {
+    let _lock = HOOK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_violation_hook(hook);
+    assert!(take_violation_hook().is_some());
+    assert!(take_violation_hook().is_none());
+    set_violation_hook(hook);
+}
@@ -0,0 +1,63 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
+use qadapt::enter_protected;
+use qadapt::set_immediate_panic;
+use qadapt::ViolationKind;
+use qadapt::QADAPT;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+/// Immediate panics are only supported in debug builds
+fn panics_immediately() -> bool {
+    qadapt::is_active() && cfg!(debug_assertions)
+}
+
+#[test]
+fn immediate_allocate() {
+    set_immediate_panic(true);
+
+    let violation = violation_in(|| {
+        enter_protected();
+        let _b = std::hint::black_box(Box::new(12));
+        // Without immediate panics, this region would need to be exited
+        // before the allocation gets reported
+    });
+    if panics_immediately() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Allocation);
+    }
+}
+
+#[test]
+fn immediate_drop() {
+    set_immediate_panic(true);
+
+    let v = std::hint::black_box(Box::new(12));
+    let violation = violation_in(|| {
+        enter_protected();
+        drop(v);
+    });
+    if panics_immediately() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Deallocation);
+    }
+}
+
+#[test]
+fn immediate_realloc() {
+    set_immediate_panic(true);
+
+    let mut v: Vec<u8> = Vec::with_capacity(16);
+    let violation = violation_in(move || {
+        enter_protected();
+        v.reserve_exact(32);
+    });
+    if panics_immediately() {
+        assert_eq!(
+            violation.unwrap().kind,
+            ViolationKind::Reallocation { new_size: 32 }
+        );
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 #[test]
 fn is_inactive() {
     assert!(!qadapt::is_active());
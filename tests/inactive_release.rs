@@ -1,9 +1,11 @@
+#![allow(deprecated)]
+
 use qadapt::QADAPT;
 
 #[global_allocator]
 static Q: QADAPT = QADAPT;
 
-#[cfg(not(debug_assertions))]
+#[cfg(not(any(debug_assertions, feature = "release-checks")))]
 #[test]
 fn release_only_inactive() {
     assert!(!qadapt::is_active());
@@ -0,0 +1,82 @@
+#![allow(deprecated)]
+
+use qadapt::allow_allocations;
+use qadapt::assert_no_alloc;
+use qadapt::no_alloc;
+use qadapt::protect;
+use qadapt::AllocationViolation;
+use qadapt::QADAPT;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+fn panic_message<F: FnOnce() + std::panic::UnwindSafe>(f: F) -> Option<String> {
+    let err = catch_unwind(f).err()?;
+    let violation = err.downcast::<AllocationViolation>().unwrap();
+    Some(violation.to_string())
+}
+
+#[no_alloc]
+fn allocates() -> Box<u32> {
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn no_alloc_fn_path() {
+    let message = panic_message(|| {
+        allocates();
+    });
+
+    if qadapt::is_active() {
+        assert!(message.unwrap().starts_with(
+            "Unexpected allocation for size 4 inside #[no_alloc] fn locations::allocates (tests/locations.rs:20)"
+        ));
+    }
+}
+
+#[test]
+fn assert_macro_location() {
+    let line = line!() + 2;
+    let message = panic_message(|| {
+        assert_no_alloc!(std::hint::black_box(Box::new(12u32)));
+    });
+
+    if qadapt::is_active() {
+        let expected = format!("inside assert_no_alloc! (tests/locations.rs:{})", line);
+        assert!(message.unwrap().contains(&expected));
+    }
+}
+
+#[test]
+fn outer_regions_named() {
+    let line = line!() + 2;
+    let message = panic_message(|| {
+        let _guard = protect();
+        allocates();
+    });
+
+    if qadapt::is_active() {
+        let expected = format!(
+            "inside #[no_alloc] fn locations::allocates (tests/locations.rs:20), \
+             within protect() (tests/locations.rs:{}), protection level: 2",
+            line
+        );
+        assert!(message.unwrap().contains(&expected));
+    }
+}
+
+#[test]
+fn allowed_regions_stop_the_search() {
+    let message = panic_message(|| {
+        let _guard = protect();
+        let _allow = allow_allocations();
+        allocates();
+    });
+
+    if qadapt::is_active() {
+        let message = message.unwrap();
+        assert!(message.contains("protection level: 1"));
+        assert!(!message.contains("within"));
+    }
+}
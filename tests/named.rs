@@ -0,0 +1,123 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
+use qadapt::allow_allocations;
+use qadapt::assert_no_alloc;
+use qadapt::current_regions;
+use qadapt::enter_protected_named;
+use qadapt::exit_protected;
+use qadapt::no_alloc;
+use qadapt::protect;
+use qadapt::protect_named;
+use qadapt::region_violation_count;
+use qadapt::QADAPT;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+fn regions() -> [Option<&'static str>; 4] {
+    let mut names = current_regions();
+    [names.next(), names.next(), names.next(), names.next()]
+}
+
+#[no_alloc]
+fn fn_regions() -> [Option<&'static str>; 4] {
+    regions()
+}
+
+#[no_alloc]
+fn allocates() -> Box<u32> {
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn named_region_in_message() {
+    let violation = violation_in(|| {
+        enter_protected_named("order_book::match");
+        let _b = std::hint::black_box(Box::new(12u32));
+        exit_protected();
+    });
+
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.region, Some("order_book::match"));
+        assert!(violation
+            .to_string()
+            .starts_with("Unexpected allocation for size 4 inside region \"order_book::match\""));
+    }
+}
+
+#[test]
+fn named_assert_macro() {
+    let violation = violation_in(|| {
+        assert_no_alloc!("named_macro", std::hint::black_box(Box::new(12u32)));
+    });
+
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.region, Some("named_macro"));
+        assert!(violation
+            .to_string()
+            .contains("inside region \"named_macro\""));
+    }
+}
+
+#[test]
+fn named_assert_macro_value() {
+    let x = assert_no_alloc!("math", 2 + 2);
+    assert_eq!(x, 4);
+}
+
+#[test]
+fn current_regions_outermost_first() {
+    assert_eq!(regions(), [None; 4]);
+
+    let _outer = protect_named("outer");
+    let _unnamed = protect();
+    let _inner = protect_named("inner");
+
+    if qadapt::is_active() {
+        assert_eq!(regions(), [Some("outer"), Some("inner"), None, None]);
+        assert_eq!(
+            fn_regions(),
+            [
+                Some("outer"),
+                Some("inner"),
+                Some("named::fn_regions"),
+                None
+            ]
+        );
+    } else {
+        assert_eq!(regions(), [None; 4]);
+    }
+}
+
+#[test]
+fn current_regions_skips_allowed() {
+    let _outer = protect_named("suspended");
+    let _allow = allow_allocations();
+    let _inner = protect_named("checked");
+
+    if qadapt::is_active() {
+        assert_eq!(regions(), [Some("checked"), None, None, None]);
+    }
+}
+
+#[test]
+fn violations_counted_by_region() {
+    let _ = catch_unwind(|| {
+        enter_protected_named("counted_region");
+        let _b = std::hint::black_box(Box::new(12u32));
+        exit_protected();
+    });
+    let _ = catch_unwind(allocates);
+
+    if qadapt::is_active() {
+        assert_eq!(region_violation_count("counted_region"), 1);
+        assert_eq!(region_violation_count("named::allocates"), 1);
+    }
+    assert_eq!(region_violation_count("never_entered"), 0);
+}
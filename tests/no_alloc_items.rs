@@ -0,0 +1,179 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::expected_level;
+use common::violation_in;
+use qadapt::no_alloc;
+use qadapt::protection_level;
+use qadapt::QADAPT;
+use std::hint::black_box;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+struct Engine {
+    samples: Vec<u32>,
+}
+
+#[no_alloc(except(new))]
+impl Engine {
+    fn new() -> Engine {
+        Engine {
+            samples: vec![1, 2, 3],
+        }
+    }
+
+    const fn limit() -> usize {
+        3
+    }
+
+    fn level(&self) -> usize {
+        protection_level()
+    }
+
+    fn tick(&mut self) -> u32 {
+        self.samples.iter().sum()
+    }
+
+    fn record(&mut self, sample: u32) {
+        self.samples.push(sample);
+    }
+
+    #[no_alloc(skip)]
+    fn reset(&mut self) {
+        self.samples = Vec::with_capacity(Engine::limit());
+    }
+}
+
+trait Source {
+    fn read(&self) -> Box<u32>;
+}
+
+#[no_alloc]
+impl Source for Engine {
+    fn read(&self) -> Box<u32> {
+        black_box(Box::new(self.samples[0]))
+    }
+}
+
+#[test]
+fn impl_methods_protected() {
+    let mut engine = Engine::new();
+    assert_eq!(engine.level(), expected_level(1));
+    assert_eq!(engine.tick(), 6);
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn impl_skip_and_except() {
+    let mut engine = Engine::new();
+    engine.reset();
+    assert_eq!(engine.samples.capacity(), 3);
+}
+
+#[test]
+fn impl_method_allocates() {
+    let violation = violation_in(|| {
+        let mut engine = Engine::new();
+        engine.record(4);
+    });
+
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.region, Some("no_alloc_items::Engine::record"));
+        // Methods are reported where they're defined, rather than at the attribute
+        assert!(violation.to_string().contains(
+            "inside #[no_alloc] fn no_alloc_items::Engine::record (tests/no_alloc_items.rs:39)"
+        ));
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[test]
+fn trait_impl_method_allocates() {
+    let engine = Engine::new();
+    let violation = violation_in(move || {
+        engine.read();
+    });
+
+    if qadapt::is_active() {
+        assert_eq!(
+            violation.unwrap().region,
+            Some("no_alloc_items::Engine::read")
+        );
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[no_alloc]
+mod hot_path {
+    use qadapt::protection_level;
+
+    pub fn level() -> usize {
+        protection_level()
+    }
+
+    pub fn allocates() -> Box<u32> {
+        std::hint::black_box(Box::new(12))
+    }
+
+    #[no_alloc(skip)]
+    pub fn setup() -> Vec<u32> {
+        vec![1, 2, 3]
+    }
+
+    pub struct Buffer(pub [u8; 4]);
+
+    impl Buffer {
+        pub fn level(&self) -> usize {
+            protection_level() + self.0.len() - 4
+        }
+    }
+
+    #[no_alloc(except(build))]
+    pub mod nested {
+        pub fn build() -> Box<u32> {
+            Box::new(1)
+        }
+
+        pub fn allocates() -> Box<u32> {
+            std::hint::black_box(Box::new(12))
+        }
+    }
+}
+
+#[test]
+fn mod_functions_protected() {
+    assert_eq!(hot_path::level(), expected_level(1));
+    assert_eq!(hot_path::Buffer([0; 4]).level(), expected_level(1));
+    assert_eq!(hot_path::setup().len(), 3);
+    assert_eq!(*hot_path::nested::build(), 1);
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn mod_function_allocates() {
+    let violation = violation_in(|| {
+        hot_path::allocates();
+    });
+    let nested = violation_in(|| {
+        hot_path::nested::allocates();
+    });
+
+    if qadapt::is_active() {
+        assert_eq!(
+            violation.unwrap().region,
+            Some("no_alloc_items::hot_path::allocates")
+        );
+        assert_eq!(
+            nested.unwrap().region,
+            Some("no_alloc_items::hot_path::nested::allocates")
+        );
+    } else {
+        assert!(violation.is_none());
+        assert!(nested.is_none());
+    }
+}
@@ -0,0 +1,203 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
+use qadapt::allow_alloc;
+use qadapt::no_alloc;
+use qadapt::region_stats;
+use qadapt::region_violation_count;
+use qadapt::ViolationKind;
+use qadapt::QADAPT;
+use std::hint::black_box;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[no_alloc(allow_dealloc)]
+fn drop_buffer(buffer: Vec<u8>) {
+    drop(buffer);
+}
+
+#[no_alloc(allow_dealloc)]
+fn allocate_and_drop() {
+    drop(black_box(Box::new(12u32)));
+}
+
+#[test]
+fn allow_dealloc() {
+    drop_buffer(vec![1, 2, 3]);
+
+    let violation = violation_in(allocate_and_drop);
+    if qadapt::is_active() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Allocation);
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[no_alloc(allow_alloc)]
+fn make_buffer() -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(1);
+    buffer.extend_from_slice(&[1, 2]);
+    buffer
+}
+
+#[test]
+fn allow_alloc() {
+    assert_eq!(make_buffer(), [1, 2]);
+
+    let buffer = vec![1, 2, 3];
+    let violation = violation_in(move || {
+        #[no_alloc(allow_alloc)]
+        fn grow_and_drop(mut buffer: Vec<u8>) {
+            buffer.push(4);
+            drop(buffer);
+        }
+        grow_and_drop(buffer)
+    });
+    if qadapt::is_active() {
+        assert_eq!(violation.unwrap().kind, ViolationKind::Deallocation);
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[no_alloc(policy = "count", name = "counted_options")]
+fn counted() -> Box<u32> {
+    black_box(Box::new(12))
+}
+
+#[test]
+fn policy_and_name() {
+    // The count policy doesn't panic
+    let b = counted();
+    assert_eq!(*b, 12);
+
+    if qadapt::is_active() {
+        assert_eq!(region_violation_count("counted_options"), 1);
+    }
+    assert_eq!(region_stats("counted_options").allocations, 1);
+    assert_eq!(region_stats("no_alloc_options::counted").allocations, 0);
+}
+
+#[no_alloc(name = "renamed")]
+fn renamed() -> Box<u32> {
+    black_box(Box::new(12))
+}
+
+#[test]
+fn name_in_violation() {
+    let violation = violation_in(|| {
+        renamed();
+    });
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.region, Some("renamed"));
+        assert!(violation.to_string().contains("inside region \"renamed\""));
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+struct Pool {
+    free: Vec<String>,
+}
+
+#[no_alloc(allow_dealloc, policy = "count")]
+impl Pool {
+    fn clear(&mut self) {
+        self.free.clear();
+    }
+
+    fn grow(&mut self) {
+        self.free.push(String::from("buffer"));
+    }
+
+    #[no_alloc(policy = "panic", name = "pool_refill")]
+    fn refill(&mut self) {
+        self.free.push(String::from("buffer"));
+    }
+}
+
+#[test]
+fn impl_options() {
+    let mut pool = Pool {
+        free: vec![String::from("a"), String::from("b")],
+    };
+    pool.clear();
+    pool.grow();
+    if qadapt::is_active() {
+        assert_eq!(region_violation_count("no_alloc_options::Pool::grow"), 1);
+        assert_eq!(region_violation_count("no_alloc_options::Pool::clear"), 0);
+    }
+
+    let violation = violation_in(move || pool.refill());
+    if qadapt::is_active() {
+        assert_eq!(violation.unwrap().region, Some("pool_refill"));
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[no_alloc(allow_alloc)]
+fn relaxed_alloc() -> Box<u32> {
+    black_box(Box::new(12))
+}
+
+#[no_alloc(allow_dealloc)]
+fn relaxed_dealloc(b: Box<u32>) {
+    drop(b);
+}
+
+#[no_alloc(policy = "ignore")]
+fn ignored() -> Box<u32> {
+    black_box(Box::new(12))
+}
+
+#[no_alloc(name = "strict_caller")]
+fn strict_caller(callee: fn() -> Box<u32>) -> u32 {
+    *callee()
+}
+
+#[no_alloc(name = "strict_dropper")]
+fn strict_dropper(b: Box<u32>) {
+    relaxed_dealloc(b);
+}
+
+#[test]
+fn options_dont_relax_caller() {
+    for callee in [relaxed_alloc as fn() -> Box<u32>, ignored] {
+        let violation = violation_in(move || {
+            strict_caller(callee);
+        });
+        if qadapt::is_active() {
+            assert_eq!(violation.unwrap().region, Some("strict_caller"));
+        } else {
+            assert!(violation.is_none());
+        }
+    }
+
+    let b = Box::new(12);
+    let violation = violation_in(move || strict_dropper(b));
+    if qadapt::is_active() {
+        let violation = violation.unwrap();
+        assert_eq!(violation.kind, ViolationKind::Deallocation);
+        assert_eq!(violation.region, Some("strict_dropper"));
+    } else {
+        assert!(violation.is_none());
+    }
+}
+
+#[no_alloc(name = "allowing_caller")]
+fn allowing_caller() -> u32 {
+    let b = allow_alloc!(relaxed_alloc());
+    let value = *b;
+    allow_alloc!(drop(b));
+    value
+}
+
+#[test]
+fn allow_alloc_suspends_caller() {
+    assert_eq!(allowing_caller(), 12);
+}
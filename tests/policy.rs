@@ -0,0 +1,99 @@
+#![allow(deprecated)]
+
+mod common;
+
+use common::violation_in;
+use qadapt::enter_protected;
+use qadapt::enter_protected_with_policy;
+use qadapt::exit_protected;
+use qadapt::protection_level;
+use qadapt::violation_count;
+use qadapt::ViolationKind;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::sync::Mutex;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+// The violation count is global, so tests that check it can't run at the same time
+static COUNT_LOCK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn region_log() {
+    let _lock = COUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let before = violation_count();
+
+    enter_protected_with_policy(ViolationPolicy::Log);
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+
+    // Logged violations don't panic, but are still counted
+    if qadapt::is_active() {
+        assert_eq!(violation_count(), before + 1);
+    } else {
+        assert_eq!(violation_count(), before);
+    }
+}
+
+#[test]
+fn region_count() {
+    let _lock = COUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let before = violation_count();
+
+    enter_protected_with_policy(ViolationPolicy::Count);
+    let b = std::hint::black_box(Box::new(12));
+    drop(b);
+    exit_protected();
+
+    if qadapt::is_active() {
+        assert_eq!(violation_count(), before + 2);
+    } else {
+        assert_eq!(violation_count(), before);
+    }
+}
+
+#[test]
+fn region_ignore() {
+    let _lock = COUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let before = violation_count();
+
+    enter_protected_with_policy(ViolationPolicy::Ignore);
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+
+    assert_eq!(violation_count(), before);
+}
+
+#[test]
+#[cfg_attr(any(debug_assertions, feature = "release-checks"), should_panic)]
+fn nested_default_policy() {
+    let _lock = COUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    enter_protected_with_policy(ViolationPolicy::Log);
+    // The innermost region uses the global policy, which panics
+    enter_protected();
+    let _b = std::hint::black_box(Box::new(12));
+    exit_protected();
+    exit_protected();
+}
+
+#[test]
+fn nested_ignore_reported_by_outer_region() {
+    let _lock = COUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    enter_protected();
+    let violation = violation_in(|| {
+        enter_protected_with_policy(ViolationPolicy::Ignore);
+        let _b = std::hint::black_box(Box::new(12));
+        exit_protected();
+    });
+
+    if qadapt::is_active() {
+        // Ignoring the violation here doesn't stop the outer region from reporting it
+        // once the inner region is exited, and reporting it cleans up both regions
+        assert_eq!(violation.unwrap().kind, ViolationKind::Allocation);
+        assert_eq!(protection_level(), 0);
+    } else {
+        assert!(violation.is_none());
+        exit_protected();
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(deprecated, clippy::needless_return)]
+
 use std::io;
 
 use qadapt::no_alloc;
@@ -147,12 +149,92 @@ fn macro_closure() {
 #[test]
 #[no_alloc]
 fn macro_release_safe() {
-    #[cfg(debug_assertions)]
+    #[cfg(any(debug_assertions, feature = "release-checks"))]
     {
         assert_eq!(1, ::qadapt::protection_level());
     }
-    #[cfg(not(debug_assertions))]
+    #[cfg(not(any(debug_assertions, feature = "release-checks")))]
     {
         assert_eq!(0, ::qadapt::protection_level());
     }
 }
+
+#[no_alloc]
+fn logical_or(a: bool, b: bool, x: u8, y: u8) -> (bool, u8) {
+    #[allow_alloc]
+    let v = Box::new(x | y);
+    let or = a || b;
+    #[allow_alloc]
+    drop(v);
+    (or, x | y)
+}
+
+#[test]
+fn macro_bit_or() {
+    assert_eq!((true, 3), logical_or(false, true, 1, 2));
+    assert_eq!(0, ::qadapt::protection_level());
+}
+
+#[no_alloc]
+fn try_parse(s: &str) -> Result<u8, std::num::ParseIntError> {
+    let x: u8 = s.parse()?;
+    Ok(x + 1)
+}
+
+#[test]
+fn macro_try_exits_protection() {
+    assert_eq!(Ok(2), try_parse("1"));
+    assert_eq!(0, ::qadapt::protection_level());
+    assert!(try_parse("not a number").is_err());
+    assert_eq!(0, ::qadapt::protection_level());
+}
+
+#[no_alloc]
+fn labeled_break(limit: u32) -> u32 {
+    let mut total = 0;
+    'outer: for i in 0..10 {
+        for j in 0..10 {
+            if i * j > limit {
+                break 'outer;
+            }
+            total += 1;
+        }
+    }
+    let found = 'search: {
+        if limit > 50 {
+            break 'search true;
+        }
+        false
+    };
+    total + found as u32
+}
+
+#[test]
+fn macro_labeled_break() {
+    assert_eq!(11, labeled_break(0));
+    assert_eq!(101, labeled_break(100));
+    assert_eq!(0, ::qadapt::protection_level());
+}
+
+#[no_alloc]
+fn closure_return(v: &[u8]) -> Option<u8> {
+    let first_even = |v: &[u8]| {
+        for x in v {
+            if x % 2 == 0 {
+                return Some(*x);
+            }
+        }
+        None
+    };
+    let found = first_even(v);
+    #[cfg(any(debug_assertions, feature = "release-checks"))]
+    assert_eq!(1, ::qadapt::protection_level());
+    found
+}
+
+#[test]
+fn macro_closure_return() {
+    assert_eq!(Some(4), closure_return(&[1, 3, 4, 5]));
+    assert_eq!(None, closure_return(&[1]));
+    assert_eq!(0, ::qadapt::protection_level());
+}
@@ -0,0 +1,157 @@
+#![allow(deprecated)]
+
+use qadapt::all_region_stats;
+use qadapt::allow_alloc;
+use qadapt::assert_no_alloc;
+use qadapt::enter_protected;
+use qadapt::exit_protected;
+use qadapt::no_alloc;
+use qadapt::protect_named;
+use qadapt::region_stats;
+use qadapt::set_violation_policy;
+use qadapt::AllocStats;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::env;
+use std::hint::black_box;
+use std::process::Command;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+// Counters are kept in both debug and release builds;
+// only count violations so the tests don't panic when checks are enabled
+fn count_violations() {
+    set_violation_policy(ViolationPolicy::Count);
+}
+
+#[no_alloc]
+fn allocates() {
+    black_box(Box::new(12u32));
+}
+
+#[no_alloc]
+fn recurse(n: u32) {
+    if n > 0 {
+        recurse(n - 1);
+    } else {
+        black_box(Box::new(12u32));
+    }
+}
+
+#[test]
+fn named_region() {
+    count_violations();
+
+    let guard = protect_named("named_region");
+    let b = black_box(Box::new(12u32));
+    drop(b);
+    drop(guard);
+    // Not counted after the region is exited
+    black_box(Box::new(12u32));
+
+    let stats = region_stats("named_region");
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.deallocations, 1);
+    assert_eq!(stats.bytes_allocated, 4);
+    assert_eq!(stats.bytes_deallocated, 4);
+}
+
+#[test]
+fn unknown_region() {
+    assert_eq!(region_stats("never_entered"), AllocStats::default());
+}
+
+#[test]
+fn enclosing_regions() {
+    count_violations();
+
+    {
+        let _guard = protect_named("enclosing_regions");
+        // Unnamed regions count towards the named regions around them
+        enter_protected();
+        allocates();
+        exit_protected();
+    }
+
+    assert_eq!(region_stats("enclosing_regions").allocations, 1);
+    assert!(region_stats("region_stats::allocates").allocations >= 1);
+}
+
+#[test]
+fn allowed_not_counted() {
+    count_violations();
+
+    {
+        let _guard = protect_named("allowed_not_counted");
+        allow_alloc!(drop(black_box(Box::new(12u32))));
+    }
+
+    assert_eq!(region_stats("allowed_not_counted"), AllocStats::default());
+}
+
+#[test]
+fn recursion_counted_once() {
+    count_violations();
+
+    recurse(3);
+
+    let stats = region_stats("region_stats::recurse");
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.deallocations, 1);
+}
+
+#[test]
+fn listed() {
+    count_violations();
+
+    {
+        let _guard = protect_named("listed");
+        black_box(Box::new(12u32));
+    }
+
+    let (_, stats) = all_region_stats()
+        .find(|(name, _)| *name == "listed")
+        .unwrap();
+    assert_eq!(stats.allocations, 1);
+}
+
+#[test]
+fn literal_name() {
+    count_violations();
+
+    for _ in 0..3 {
+        assert_no_alloc!("literal_name", black_box(Box::new(12u32)));
+    }
+
+    assert_eq!(region_stats("literal_name").allocations, 3);
+}
+
+#[test]
+fn out_of_counters() {
+    // Run in a new process, so the other tests still get their counters
+    if env::var_os("QADAPT_STATS_CHILD").is_none() {
+        let output = Command::new(env::current_exe().unwrap())
+            .args(["out_of_counters", "--exact", "--test-threads=1"])
+            .env("QADAPT_STATS_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(stderr.matches("region counters are in use").count(), 1);
+        return;
+    }
+
+    count_violations();
+    let names: Vec<&'static str> = (0..70)
+        .map(|i| &*Box::leak(format!("region_{}", i).into_boxed_str()))
+        .collect();
+    for name in &names {
+        let _guard = protect_named(name);
+        black_box(Box::new(12u32));
+    }
+
+    assert_eq!(region_stats("region_63").allocations, 1);
+    assert_eq!(region_stats("region_64"), AllocStats::default());
+    assert_eq!(all_region_stats().count(), 64);
+}
@@ -0,0 +1,54 @@
+#![cfg(all(not(debug_assertions), feature = "release-checks"))]
+#![allow(deprecated)]
+
+use qadapt::assert_no_alloc;
+use qadapt::no_alloc;
+use qadapt::set_immediate_panic;
+use qadapt::AllocationViolation;
+use qadapt::QADAPT;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[no_alloc]
+fn allocates() -> Box<u32> {
+    std::hint::black_box(Box::new(12))
+}
+
+#[test]
+fn active_in_release() {
+    assert!(qadapt::is_active());
+}
+
+#[test]
+fn checks_in_release() {
+    let err = catch_unwind(|| {
+        allocates();
+    })
+    .unwrap_err();
+    assert!(err.is::<AllocationViolation>());
+}
+
+#[test]
+fn optimized_code_allowed() {
+    let x = assert_no_alloc!(std::hint::black_box(2) + 2);
+    assert_eq!(x, 4);
+}
+
+#[test]
+fn immediate_panic_ignored() {
+    set_immediate_panic(true);
+
+    let mut finished = false;
+    let err = catch_unwind(std::panic::AssertUnwindSafe(|| {
+        assert_no_alloc!({
+            std::hint::black_box(Box::new(12));
+            finished = true;
+        });
+    }))
+    .unwrap_err();
+    // The violation is reported when the region exits instead
+    assert!(err.is::<AllocationViolation>());
+    assert!(finished);
+}
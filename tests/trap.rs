@@ -0,0 +1,34 @@
+#![cfg(unix)]
+#![allow(deprecated)]
+
+mod common;
+
+use common::count_traps;
+use qadapt::protect_with_policy;
+use qadapt::violation_count;
+use qadapt::ViolationPolicy;
+use qadapt::QADAPT;
+use std::sync::atomic::Ordering;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[test]
+fn trap_policy() {
+    // Stand in for a debugger, so the trap doesn't end the test
+    let traps = count_traps();
+    let before = violation_count();
+
+    {
+        let _guard = protect_with_policy(ViolationPolicy::Trap);
+        let _b = std::hint::black_box(Box::new(12));
+    }
+
+    if qadapt::is_active() {
+        // Both the allocation and the drop
+        assert_eq!(traps.load(Ordering::SeqCst), 2);
+        assert!(violation_count() >= before + 2);
+    } else {
+        assert_eq!(traps.load(Ordering::SeqCst), 0);
+    }
+}
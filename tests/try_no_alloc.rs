@@ -0,0 +1,107 @@
+#![allow(deprecated)]
+
+use qadapt::assert_no_alloc;
+use qadapt::no_alloc;
+use qadapt::protection_level;
+use qadapt::set_immediate_panic;
+use qadapt::try_no_alloc;
+use qadapt::violation_count;
+use qadapt::ViolationKind;
+use qadapt::QADAPT;
+use std::hint::black_box;
+use std::panic::catch_unwind;
+
+#[global_allocator]
+static Q: QADAPT = QADAPT;
+
+#[no_alloc]
+fn allocates() -> Box<u32> {
+    black_box(Box::new(12))
+}
+
+#[test]
+fn returns_result() {
+    assert_eq!(try_no_alloc(|| 2 + 2).ok(), Some(4));
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn captures_allocation() {
+    let res = try_no_alloc(|| black_box(Box::new(12u32)));
+    if qadapt::is_active() {
+        let violation = res.unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::Allocation);
+        assert_eq!(violation.size, 4);
+        // The box is freed again once the region is exited
+        assert_eq!(violation.others, 0);
+    } else {
+        assert_eq!(*res.unwrap(), 12);
+    }
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn captures_drop() {
+    let b = black_box(Box::new(12u32));
+    let res = try_no_alloc(move || drop(b));
+    if qadapt::is_active() {
+        assert_eq!(res.unwrap_err().kind, ViolationKind::Deallocation);
+    }
+}
+
+#[test]
+fn captures_nested_regions() {
+    let res = try_no_alloc(|| {
+        allocates();
+        assert_no_alloc!(black_box(Box::new(1u8)));
+    });
+    if qadapt::is_active() {
+        let violation = res.unwrap_err();
+        assert_eq!(violation.region, Some("try_no_alloc::allocates"));
+        assert_eq!(violation.protection_level, 2);
+        assert!(violation.others > 0);
+    }
+    assert_eq!(protection_level(), 0);
+}
+
+#[test]
+fn ignores_immediate_panic() {
+    set_immediate_panic(true);
+    let res = try_no_alloc(|| black_box(vec![1u8, 2, 3]));
+    if qadapt::is_active() {
+        assert!(res.is_err());
+    }
+    set_immediate_panic(false);
+}
+
+#[test]
+fn not_counted() {
+    let before = violation_count();
+    let _ = try_no_alloc(|| black_box(Box::new(12u32)));
+    assert_eq!(violation_count(), before);
+}
+
+#[test]
+fn nested_calls_are_separate() {
+    let res = try_no_alloc(|| {
+        // Leak the box, so the outer call doesn't see it freed
+        let inner = try_no_alloc(|| std::mem::forget(black_box(Box::new(12u32))));
+        if qadapt::is_active() {
+            assert!(inner.is_err());
+        }
+        2 + 2
+    });
+    assert_eq!(res.ok(), Some(4));
+}
+
+#[test]
+fn panics_pass_through() {
+    let res = catch_unwind(|| {
+        let _ = try_no_alloc(|| panic!("user panic"));
+    });
+    assert!(res.is_err());
+    assert_eq!(protection_level(), 0);
+
+    // Nothing is left over from the panicking call
+    assert_eq!(try_no_alloc(|| 1).ok(), Some(1));
+}
@@ -0,0 +1,18 @@
+#![allow(deprecated)]
+
+use qadapt::alloc_budget;
+
+#[alloc_budget(count = 1, size = 16)]
+fn unknown() {}
+
+#[alloc_budget(count = 1, count = 2)]
+fn duplicate() {}
+
+#[alloc_budget()]
+fn missing() {}
+
+fn main() {
+    unknown();
+    duplicate();
+    missing();
+}
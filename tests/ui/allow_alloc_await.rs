@@ -0,0 +1,18 @@
+#![allow(deprecated)]
+
+use qadapt::no_alloc;
+
+async fn fetch() -> Vec<u8> {
+    Vec::new()
+}
+
+#[no_alloc]
+async fn handler() -> usize {
+    #[allow_alloc]
+    let v = fetch().await;
+    v.len()
+}
+
+fn main() {
+    drop(handler());
+}
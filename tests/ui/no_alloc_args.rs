@@ -0,0 +1,30 @@
+#![allow(deprecated)]
+
+use qadapt::no_alloc;
+
+#[no_alloc(strict)]
+fn unknown() {}
+
+#[no_alloc(policy = "shout")]
+fn unknown_policy() {}
+
+#[no_alloc(allow_alloc, allow_dealloc)]
+fn allow_both() {}
+
+#[no_alloc(policy = "log", policy = "count")]
+fn duplicate() {}
+
+struct Engine;
+
+#[no_alloc(name = "engine")]
+impl Engine {
+    fn tick(&self) {}
+}
+
+fn main() {
+    unknown();
+    unknown_policy();
+    allow_both();
+    duplicate();
+    Engine.tick();
+}
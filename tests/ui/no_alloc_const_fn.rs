@@ -0,0 +1,21 @@
+#![allow(deprecated)]
+
+use qadapt::alloc_budget;
+use qadapt::no_alloc;
+
+#[no_alloc]
+const fn square(x: u32) -> u32 {
+    x * x
+}
+
+#[alloc_budget(count = 1)]
+const fn cube(x: u32) -> u32 {
+    x * x * x
+}
+
+const SQUARE: u32 = square(3);
+const CUBE: u32 = cube(3);
+
+fn main() {
+    assert_eq!(SQUARE + CUBE, 36);
+}
@@ -0,0 +1,23 @@
+#![allow(deprecated)]
+
+use qadapt::no_alloc;
+
+#[no_alloc(skip)]
+fn skipped() {}
+
+#[no_alloc(except(new))]
+fn excepted() {}
+
+struct Engine;
+
+#[no_alloc]
+impl Engine {
+    #[no_alloc(except(tick))]
+    fn run(&self) {}
+}
+
+fn main() {
+    skipped();
+    excepted();
+    Engine.run();
+}
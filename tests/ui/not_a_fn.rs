@@ -0,0 +1,10 @@
+#![allow(deprecated)]
+
+use qadapt::no_alloc;
+
+#[no_alloc]
+struct Engine;
+
+fn main() {
+    let _ = Engine;
+}